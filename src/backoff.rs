@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponentially growing delays between retries, capped at a maximum.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt. The delay is jittered between half and all of
+    /// the current step, so that many workers failing at once do not retry in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.current;
+        self.current = (self.current * 2).min(self.max);
+        rand::thread_rng().gen_range(step / 2..=step)
+    }
+
+    /// Starts over with the initial delay, to be called after a successful attempt.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
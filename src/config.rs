@@ -0,0 +1,808 @@
+use std::{
+    collections::HashSet, env, fs, iter, net::SocketAddr, path::Path, path::PathBuf, sync::Arc,
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use redis::{ClientTlsConfig, TlsCertificates};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    sentinel::{ConnectionOptions, Fencing, MasterCheck},
+    service::{IpFamily, Resolution, ServiceTarget, DEFAULT_PORT_NAME},
+    Error,
+};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_LEASE_NAME: &str = "redis-sentinel-service-controller";
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(15);
+const DEFAULT_FENCING_DELAY: Duration = Duration::from_secs(30);
+
+/// Keeps Kubernetes Services pointed at the masters of Sentinel-monitored Redis instances.
+///
+/// Every option can also be given as an environment variable or in a YAML or TOML config file,
+/// in that order of precedence.
+#[derive(Parser, Deserialize, Default, Debug)]
+#[command(version, about)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Options {
+    /// A YAML or TOML file to read defaults for all other options from.
+    #[arg(long, env = "CONFIG_FILE")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Prints the RedisSentinelService CRD and exits.
+    #[arg(long)]
+    #[serde(skip)]
+    pub print_crd: bool,
+
+    /// The comma separated host:port addresses of the sentinels.
+    #[arg(long, env = "SENTINELS", value_delimiter = ',')]
+    sentinels: Vec<String>,
+    /// How many sentinels need to agree on a master [default: a majority]
+    #[arg(long, env = "SENTINEL_QUORUM")]
+    quorum: Option<usize>,
+    /// The name the master is monitored under by the sentinels.
+    #[arg(long, env = "MASTER_NAME")]
+    master_name: Option<String>,
+    /// The Service to point to the master.
+    #[arg(long, env = "SERVICE_NAME")]
+    service_name: Option<String>,
+    /// The Service to point to the healthy replicas of the master.
+    #[arg(long, env = "REPLICA_SERVICE_NAME")]
+    replica_service_name: Option<String>,
+    /// Additional masters as <master name>=[<namespace>/]<service name>[,<replica service name>].
+    #[arg(
+        long = "master",
+        env = "MASTERS",
+        value_name = "MAPPING",
+        value_delimiter = ';'
+    )]
+    #[serde(rename = "masters")]
+    masters: Vec<String>,
+    /// The namespace to create the Services in [default: the namespace of the controller]
+    #[arg(long, env = "NAMESPACE")]
+    namespace: Option<String>,
+    /// How often the sentinels are asked for the masters, e.g. 30s or 1m [default: 30s]
+    #[arg(long, env = "POLL_INTERVAL")]
+    poll_interval: Option<String>,
+    /// How long to collect a burst of updates before applying them, e.g. 500ms.
+    #[arg(long, env = "DEBOUNCE")]
+    debounce: Option<String>,
+    /// Take over Services and EndpointSlices managed by someone else.
+    #[arg(long, env = "FORCE", num_args = 0..=1, default_missing_value = "true")]
+    force: Option<bool>,
+    /// Reconcile RedisSentinelService custom resources.
+    #[arg(long, env = "CRD", num_args = 0..=1, default_missing_value = "true")]
+    crd: Option<bool>,
+    /// How to turn the hosts announced by the sentinels into endpoint addresses [default: dns]
+    #[arg(long, env = "RESOLVE")]
+    resolve: Option<ResolveMode>,
+    /// Match pods by the value of this label instead of by hostname, e.g.
+    /// statefulset.kubernetes.io/pod-name. The value is compared to the first DNS label of the
+    /// announced host, requires --resolve=pods.
+    #[arg(long, env = "POD_HOST_LABEL")]
+    pod_host_label: Option<String>,
+    /// The comma separated address families to write endpoints for, in order of preference. The
+    /// first one becomes the primary family of the Services [default: ipv4]
+    #[arg(long, env = "IP_FAMILIES", value_delimiter = ',')]
+    ip_families: Vec<AddressFamily>,
+    /// Before switching a Service, wait up to this long for the new master to report the master
+    /// ROLE, e.g. 10s. Uses the credentials and TLS settings of the sentinels [default: no check]
+    #[arg(long, env = "MASTER_CHECK_TIMEOUT")]
+    master_check_timeout: Option<String>,
+    /// Also wait for the new master to finish loading its dataset, requires
+    /// --master-check-timeout.
+    #[arg(long, env = "MASTER_CHECK_LOADING", num_args = 0..=1, default_missing_value = "true")]
+    master_check_loading: Option<bool>,
+    /// What to do with a Service when its master is objectively down or the sentinels do not
+    /// agree on one [default: keep]
+    #[arg(long, env = "FENCING")]
+    fencing: Option<FencingMode>,
+    /// How long the sentinels may disagree before the endpoints are removed, e.g. 30s, requires
+    /// --fencing=clear [default: 30s]
+    #[arg(long, env = "FENCING_DELAY")]
+    fencing_delay: Option<String>,
+    /// Remove the endpoints of the Services when shutting down, instead of leaving them pointing
+    /// to the last known master.
+    #[arg(long, env = "CLEAR_ON_SHUTDOWN", num_args = 0..=1, default_missing_value = "true")]
+    clear_on_shutdown: Option<bool>,
+    /// One of off, error, warn, info, debug or trace, optionally per module like
+    /// info,kube=warn [default: info]
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Whether to log human readable text or one JSON object per line [default: text]
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// The address to serve /metrics, /healthz and /readyz on [default: 0.0.0.0:8080]
+    #[arg(long, env = "LISTEN_ADDRESS")]
+    listen_address: Option<String>,
+
+    /// Only write to Kubernetes while holding a Lease, so multiple replicas can run.
+    #[arg(long, env = "LEADER_ELECTION", num_args = 0..=1, default_missing_value = "true")]
+    leader_election: Option<bool>,
+    /// The name of the Lease [default: redis-sentinel-service-controller]
+    #[arg(long, env = "LEASE_NAME")]
+    lease_name: Option<String>,
+    /// The namespace of the Lease [default: the namespace of the controller]
+    #[arg(long, env = "LEASE_NAMESPACE")]
+    lease_namespace: Option<String>,
+    /// How long the Lease is valid without being renewed, e.g. 15s [default: 15s]
+    #[arg(long, env = "LEASE_DURATION")]
+    lease_duration: Option<String>,
+    /// The identity of this replica in the Lease and in Events [default: the hostname]
+    #[arg(long, env = "POD_NAME")]
+    leader_identity: Option<String>,
+
+    /// The ACL user to authenticate to the sentinels as.
+    #[arg(long, env = "SENTINEL_USERNAME")]
+    sentinel_username: Option<String>,
+    /// A file containing the password of the sentinels, e.g. mounted from a Secret.
+    #[arg(long, env = "SENTINEL_PASSWORD_FILE")]
+    sentinel_password_file: Option<PathBuf>,
+    /// Connect to the sentinels using TLS, implied by the other TLS options.
+    #[arg(long, env = "SENTINEL_TLS", num_args = 0..=1, default_missing_value = "true")]
+    sentinel_tls: Option<bool>,
+    /// A PEM file with the CA certificates to verify the sentinels with.
+    #[arg(long, env = "SENTINEL_TLS_CA_FILE")]
+    sentinel_tls_ca_file: Option<PathBuf>,
+    /// A PEM file with the client certificate to present to the sentinels.
+    #[arg(long, env = "SENTINEL_TLS_CERT_FILE")]
+    sentinel_tls_cert_file: Option<PathBuf>,
+    /// A PEM file with the key of the client certificate.
+    #[arg(long, env = "SENTINEL_TLS_KEY_FILE")]
+    sentinel_tls_key_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum ResolveMode {
+    /// Look the hosts up in the DNS of the controller.
+    #[default]
+    Dns,
+    /// Map the hosts to Pods in the namespace of the Service, by IP or hostname and subdomain,
+    /// the endpoints then reference their Pods.
+    Pods,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum FencingMode {
+    /// Keep pointing to the last known master.
+    #[default]
+    Keep,
+    /// Remove the endpoints, so clients fail fast instead of writing to a demoted node.
+    Clear,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// A master and the Services to create for it.
+pub struct MasterMapping {
+    pub master_name: String,
+    pub namespace: Option<String>,
+    pub service_name: String,
+    pub replica_service_name: Option<String>,
+}
+
+pub struct LeaderElectionConfig {
+    pub lease_name: String,
+    pub lease_namespace: Option<String>,
+    pub lease_duration: Duration,
+    pub identity: String,
+}
+
+/// The validated configuration of the controller.
+pub struct Config {
+    pub sentinels: Vec<String>,
+    pub quorum: Option<usize>,
+    pub masters: Vec<MasterMapping>,
+    pub poll_interval: Duration,
+    pub debounce: Option<Duration>,
+    pub force: bool,
+    pub crd: bool,
+    pub resolution: Resolution,
+    /// The address families to write endpoints for, the preferred one first.
+    pub ip_families: Vec<IpFamily>,
+    pub master_check: Option<MasterCheck>,
+    pub fencing: Fencing,
+    pub clear_on_shutdown: bool,
+    pub log_filter: EnvFilter,
+    pub log_format: LogFormat,
+    pub listen_address: SocketAddr,
+    pub leader_election: Option<LeaderElectionConfig>,
+    /// Identifies this replica, if known.
+    pub identity: Option<String>,
+    pub connection: ConnectionOptions,
+}
+
+impl MasterMapping {
+    /// Parses a `<master name>=[<namespace>/]<service name>[,<replica service name>]` mapping.
+    fn parse(mapping: &str) -> Result<Self, Error> {
+        let invalid = || {
+            Error::InvalidConfig(format!(
+                "Invalid master mapping {}, expected <master name>=[<namespace>/]<service name>[,<replica service name>]",
+                mapping
+            ))
+        };
+        let Some((master_name, service)) = mapping.split_once('=') else {
+            return Err(invalid());
+        };
+        let (service, replica_service_name) = match service.split_once(',') {
+            Some((service, replica_name)) => (service, Some(replica_name.to_owned())),
+            None => (service, None),
+        };
+        let (namespace, service_name) = match service.split_once('/') {
+            Some((namespace, name)) => (Some(namespace.to_owned()), name),
+            None => (None, service),
+        };
+        if master_name.is_empty()
+            || service_name.is_empty()
+            || namespace.as_deref() == Some("")
+            || replica_service_name.as_deref() == Some("")
+        {
+            return Err(invalid());
+        }
+
+        Ok(MasterMapping {
+            master_name: master_name.to_owned(),
+            namespace,
+            service_name: service_name.to_owned(),
+            replica_service_name,
+        })
+    }
+
+    pub fn into_target(self, default_namespace: &str) -> (String, Arc<ServiceTarget>) {
+        let target = ServiceTarget {
+            master_name: self.master_name.clone(),
+            namespace: self
+                .namespace
+                .unwrap_or_else(|| default_namespace.to_owned()),
+            name: self.service_name,
+            replica_name: self.replica_service_name,
+            port_name: DEFAULT_PORT_NAME.to_owned(),
+            owner: None,
+        };
+        (self.master_name, Arc::new(target))
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path) {
+        Ok(content) => Ok(content),
+        Err(err) => Err(Error::InvalidConfig(format!(
+            "Failed to read {}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<Options, Error> {
+    let content = match String::from_utf8(read_file(path)?) {
+        Ok(content) => content,
+        Err(err) => {
+            return Err(Error::InvalidConfig(format!(
+                "{} is not valid UTF-8: {}",
+                path.display(),
+                err
+            )))
+        }
+    };
+    let parsed = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&content).map_err(|err| err.to_string())
+    } else {
+        serde_yaml::from_str(&content).map_err(|err| err.to_string())
+    };
+    parsed
+        .map_err(|err| Error::InvalidConfig(format!("Failed to parse {}: {}", path.display(), err)))
+}
+
+fn parse_duration(name: &str, value: &str) -> Result<Duration, Error> {
+    match humantime::parse_duration(value) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        Ok(_) => Err(Error::InvalidConfig(format!(
+            "{} must be longer than zero",
+            name
+        ))),
+        Err(err) => Err(Error::InvalidConfig(format!(
+            "Invalid duration {} for {}: {}, expected something like 30s or 1m",
+            value, name, err
+        ))),
+    }
+}
+
+/// Builds the sentinel connection options, the password is read from a file so it can be
+/// mounted from a Kubernetes Secret.
+fn load_connection_options(options: &Options) -> Result<ConnectionOptions, Error> {
+    let password = match &options.sentinel_password_file {
+        Some(path) => match String::from_utf8(read_file(path)?) {
+            Ok(password) => Some(password.trim_end_matches(['\r', '\n']).to_owned()),
+            Err(err) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Password in {} is not valid UTF-8: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        },
+        None => None,
+    };
+
+    let root_cert = options
+        .sentinel_tls_ca_file
+        .as_deref()
+        .map(read_file)
+        .transpose()?;
+    let client_tls = match (
+        &options.sentinel_tls_cert_file,
+        &options.sentinel_tls_key_file,
+    ) {
+        (Some(cert), Some(key)) => Some(ClientTlsConfig {
+            client_cert: read_file(cert)?,
+            client_key: read_file(key)?,
+        }),
+        (None, None) => None,
+        _ => {
+            return Err(Error::InvalidConfig(
+                "--sentinel-tls-cert-file and --sentinel-tls-key-file must be given together"
+                    .to_owned(),
+            ))
+        }
+    };
+    let certificates = if root_cert.is_some() || client_tls.is_some() {
+        Some(TlsCertificates {
+            client_tls,
+            root_cert,
+        })
+    } else {
+        None
+    };
+
+    Ok(ConnectionOptions {
+        username: options.sentinel_username.clone(),
+        password,
+        tls: options.sentinel_tls.unwrap_or(false) || certificates.is_some(),
+        certificates,
+    })
+}
+
+impl Options {
+    /// Fills everything not given on the command line or in the environment from the other
+    /// options.
+    fn or(self, other: Options) -> Options {
+        fn or_vec<T>(this: Vec<T>, other: Vec<T>) -> Vec<T> {
+            if this.is_empty() {
+                other
+            } else {
+                this
+            }
+        }
+
+        Options {
+            config: self.config,
+            print_crd: self.print_crd,
+            sentinels: or_vec(self.sentinels, other.sentinels),
+            quorum: self.quorum.or(other.quorum),
+            master_name: self.master_name.or(other.master_name),
+            service_name: self.service_name.or(other.service_name),
+            replica_service_name: self.replica_service_name.or(other.replica_service_name),
+            masters: or_vec(self.masters, other.masters),
+            namespace: self.namespace.or(other.namespace),
+            poll_interval: self.poll_interval.or(other.poll_interval),
+            debounce: self.debounce.or(other.debounce),
+            force: self.force.or(other.force),
+            crd: self.crd.or(other.crd),
+            resolve: self.resolve.or(other.resolve),
+            pod_host_label: self.pod_host_label.or(other.pod_host_label),
+            ip_families: or_vec(self.ip_families, other.ip_families),
+            master_check_timeout: self.master_check_timeout.or(other.master_check_timeout),
+            master_check_loading: self.master_check_loading.or(other.master_check_loading),
+            fencing: self.fencing.or(other.fencing),
+            fencing_delay: self.fencing_delay.or(other.fencing_delay),
+            clear_on_shutdown: self.clear_on_shutdown.or(other.clear_on_shutdown),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            listen_address: self.listen_address.or(other.listen_address),
+            leader_election: self.leader_election.or(other.leader_election),
+            lease_name: self.lease_name.or(other.lease_name),
+            lease_namespace: self.lease_namespace.or(other.lease_namespace),
+            lease_duration: self.lease_duration.or(other.lease_duration),
+            leader_identity: self.leader_identity.or(other.leader_identity),
+            sentinel_username: self.sentinel_username.or(other.sentinel_username),
+            sentinel_password_file: self.sentinel_password_file.or(other.sentinel_password_file),
+            sentinel_tls: self.sentinel_tls.or(other.sentinel_tls),
+            sentinel_tls_ca_file: self.sentinel_tls_ca_file.or(other.sentinel_tls_ca_file),
+            sentinel_tls_cert_file: self.sentinel_tls_cert_file.or(other.sentinel_tls_cert_file),
+            sentinel_tls_key_file: self.sentinel_tls_key_file.or(other.sentinel_tls_key_file),
+        }
+    }
+
+    /// Merges in the config file, if any, and validates the result.
+    pub fn into_config(self) -> Result<Config, Error> {
+        let options = match &self.config {
+            Some(path) => {
+                let file = read_config_file(path)?;
+                self.or(file)
+            }
+            None => self,
+        };
+
+        let mut masters = match (&options.master_name, &options.service_name) {
+            (Some(master_name), Some(service_name)) => vec![MasterMapping {
+                master_name: master_name.clone(),
+                namespace: options.namespace.clone(),
+                service_name: service_name.clone(),
+                replica_service_name: options.replica_service_name.clone(),
+            }],
+            (None, None) if options.replica_service_name.is_some() => {
+                return Err(Error::InvalidConfig(
+                    "--replica-service-name requires --master-name and --service-name".to_owned(),
+                ))
+            }
+            (None, None) => Vec::new(),
+            _ => {
+                return Err(Error::InvalidConfig(
+                    "--master-name and --service-name must be given together".to_owned(),
+                ))
+            }
+        };
+        for mapping in &options.masters {
+            let mut mapping = MasterMapping::parse(mapping)?;
+            mapping.namespace = mapping.namespace.or_else(|| options.namespace.clone());
+            masters.push(mapping);
+        }
+        // each master is followed by one set of workers, which must not share their Services
+        let mut master_names = HashSet::new();
+        let mut services = HashSet::new();
+        for mapping in &masters {
+            if !master_names.insert(&mapping.master_name) {
+                return Err(Error::InvalidConfig(format!(
+                    "Master {} is configured more than once",
+                    mapping.master_name
+                )));
+            }
+            for service in iter::once(&mapping.service_name).chain(&mapping.replica_service_name) {
+                if !services.insert((&mapping.namespace, service)) {
+                    return Err(Error::InvalidConfig(format!(
+                        "Service {}{} is configured more than once",
+                        mapping
+                            .namespace
+                            .as_ref()
+                            .map(|namespace| format!("{}/", namespace))
+                            .unwrap_or_default(),
+                        service
+                    )));
+                }
+            }
+        }
+
+        let crd = options.crd.unwrap_or(false);
+        if masters.is_empty() && !crd {
+            return Err(Error::InvalidConfig(
+                "Nothing to do, configure a master with --master-name and --service-name or --master, or enable --crd".to_owned(),
+            ));
+        }
+        if !masters.is_empty() && options.sentinels.is_empty() {
+            return Err(Error::InvalidConfig(
+                "--sentinels is required to follow the configured masters".to_owned(),
+            ));
+        }
+
+        let poll_interval = match &options.poll_interval {
+            Some(value) => parse_duration("--poll-interval", value)?,
+            None => DEFAULT_POLL_INTERVAL,
+        };
+        let debounce = options
+            .debounce
+            .as_deref()
+            .map(|value| parse_duration("--debounce", value))
+            .transpose()?;
+        let resolution = match (options.resolve.unwrap_or_default(), &options.pod_host_label) {
+            (ResolveMode::Dns, None) => Resolution::Dns,
+            (ResolveMode::Dns, Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "--pod-host-label requires --resolve=pods".to_owned(),
+                ))
+            }
+            (ResolveMode::Pods, host_label) => Resolution::Pods {
+                host_label: host_label.clone(),
+            },
+        };
+        let fencing = match (options.fencing.unwrap_or_default(), &options.fencing_delay) {
+            (FencingMode::Keep, None) => Fencing::Keep,
+            (FencingMode::Keep, Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "--fencing-delay requires --fencing=clear".to_owned(),
+                ))
+            }
+            (FencingMode::Clear, delay) => Fencing::Clear {
+                after: match delay {
+                    Some(value) => parse_duration("--fencing-delay", value)?,
+                    None => DEFAULT_FENCING_DELAY,
+                },
+            },
+        };
+        let mut ip_families = Vec::new();
+        for family in &options.ip_families {
+            let family = match family {
+                AddressFamily::Ipv4 => IpFamily::Ipv4,
+                AddressFamily::Ipv6 => IpFamily::Ipv6,
+            };
+            if ip_families.contains(&family) {
+                return Err(Error::InvalidConfig(format!(
+                    "--ip-families lists {:?} more than once",
+                    family
+                )));
+            }
+            ip_families.push(family);
+        }
+        if ip_families.is_empty() {
+            ip_families.push(IpFamily::Ipv4);
+        }
+        let log_level = options.log_level.as_deref().unwrap_or("info");
+        let log_filter = match EnvFilter::try_new(log_level) {
+            Ok(filter) => filter,
+            Err(err) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid log level {}: {}, expected something like info or info,kube=warn",
+                    log_level, err
+                )))
+            }
+        };
+        let listen_address = options
+            .listen_address
+            .as_deref()
+            .unwrap_or(DEFAULT_LISTEN_ADDRESS);
+        let listen_address = match listen_address.parse() {
+            Ok(addr) => addr,
+            Err(err) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid listen address {}: {}, expected something like 0.0.0.0:8080",
+                    listen_address, err
+                )))
+            }
+        };
+
+        let identity = options
+            .leader_identity
+            .clone()
+            .or_else(|| env::var("HOSTNAME").ok());
+        let leader_election = if options.leader_election.unwrap_or(false) {
+            let Some(identity) = identity.clone() else {
+                return Err(Error::InvalidConfig(
+                    "--leader-identity is required for leader election when HOSTNAME is not set"
+                        .to_owned(),
+                ));
+            };
+            Some(LeaderElectionConfig {
+                lease_name: options
+                    .lease_name
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_LEASE_NAME.to_owned()),
+                lease_namespace: options.lease_namespace.clone(),
+                lease_duration: match &options.lease_duration {
+                    Some(value) => parse_duration("--lease-duration", value)?,
+                    None => DEFAULT_LEASE_DURATION,
+                },
+                identity,
+            })
+        } else {
+            None
+        };
+
+        let connection = load_connection_options(&options)?;
+        let master_check = match (&options.master_check_timeout, options.master_check_loading) {
+            (Some(timeout), wait_for_loading) => Some(MasterCheck {
+                timeout: parse_duration("--master-check-timeout", timeout)?,
+                wait_for_loading: wait_for_loading.unwrap_or(false),
+                connection: connection.clone(),
+            }),
+            (None, Some(true)) => {
+                return Err(Error::InvalidConfig(
+                    "--master-check-loading requires --master-check-timeout".to_owned(),
+                ))
+            }
+            (None, _) => None,
+        };
+
+        Ok(Config {
+            connection,
+            sentinels: options.sentinels,
+            quorum: options.quorum,
+            masters,
+            poll_interval,
+            debounce,
+            force: options.force.unwrap_or(false),
+            crd,
+            resolution,
+            ip_families,
+            master_check,
+            fencing,
+            clear_on_shutdown: options.clear_on_shutdown.unwrap_or(false),
+            log_filter,
+            log_format: options.log_format.unwrap_or_default(),
+            listen_address,
+            leader_election,
+            identity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse_from(iter::once("controller").chain(args.iter().copied()))
+    }
+
+    fn config_error(args: &[&str]) -> String {
+        match options(args).into_config() {
+            Err(Error::InvalidConfig(message)) => message,
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("{:?} is valid", args),
+        }
+    }
+
+    #[test]
+    fn mapping_with_namespace_and_replicas() {
+        let mapping = MasterMapping::parse("mymaster=redis/master,replicas").unwrap();
+        assert_eq!(mapping.master_name, "mymaster");
+        assert_eq!(mapping.namespace.as_deref(), Some("redis"));
+        assert_eq!(mapping.service_name, "master");
+        assert_eq!(mapping.replica_service_name.as_deref(), Some("replicas"));
+    }
+
+    #[test]
+    fn mapping_without_namespace_or_replicas() {
+        let mapping = MasterMapping::parse("mymaster=master").unwrap();
+        assert_eq!(mapping.namespace, None);
+        assert_eq!(mapping.service_name, "master");
+        assert_eq!(mapping.replica_service_name, None);
+    }
+
+    #[test]
+    fn mapping_parts_must_not_be_empty() {
+        for mapping in [
+            "",
+            "mymaster",
+            "=master",
+            "mymaster=",
+            "mymaster=/master",
+            "mymaster=redis/",
+            "mymaster=master,",
+            "mymaster=redis/,replicas",
+        ] {
+            assert!(
+                matches!(MasterMapping::parse(mapping), Err(Error::InvalidConfig(_))),
+                "{:?} is valid",
+                mapping
+            );
+        }
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let file = options(&[
+            "--sentinels=file:26379",
+            "--poll-interval=1m",
+            "--master=file=file",
+            "--crd",
+        ]);
+        let merged = options(&["--poll-interval=5s", "--master=flag=flag"]).or(file);
+        assert_eq!(merged.poll_interval.as_deref(), Some("5s"));
+        assert_eq!(merged.masters, ["flag=flag"]);
+        assert_eq!(merged.sentinels, ["file:26379"]);
+        assert_eq!(merged.crd, Some(true));
+    }
+
+    #[test]
+    fn file_fills_in_what_the_flags_leave_out() {
+        let path = env::temp_dir().join(format!("config-test-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "sentinels: [file:26379]\nmasters: [mymaster=master]\npoll-interval: 1m\n",
+        )
+        .unwrap();
+        let config =
+            options(&["--config", path.to_str().unwrap(), "--poll-interval=5s"]).into_config();
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.poll_interval, Duration::from_secs(5));
+        assert_eq!(config.sentinels, ["file:26379"]);
+        assert_eq!(config.masters[0].service_name, "master");
+    }
+
+    #[test]
+    fn explicit_false_is_not_overridden_by_the_file() {
+        let merged = options(&["--crd=false"]).or(options(&["--crd"]));
+        assert_eq!(merged.crd, Some(false));
+    }
+
+    #[test]
+    fn master_and_service_name_go_together() {
+        for args in [
+            ["--sentinels=s:26379", "--master-name=mymaster"],
+            ["--sentinels=s:26379", "--service-name=master"],
+        ] {
+            assert_eq!(
+                config_error(&args),
+                "--master-name and --service-name must be given together"
+            );
+        }
+        assert_eq!(
+            config_error(&["--crd", "--replica-service-name=replicas"]),
+            "--replica-service-name requires --master-name and --service-name"
+        );
+    }
+
+    #[test]
+    fn dependent_options_require_their_mode() {
+        let master = ["--sentinels=s:26379", "--master=mymaster=master"];
+        for (arg, message) in [
+            (
+                "--pod-host-label=app",
+                "--pod-host-label requires --resolve=pods",
+            ),
+            (
+                "--fencing-delay=10s",
+                "--fencing-delay requires --fencing=clear",
+            ),
+            (
+                "--master-check-loading",
+                "--master-check-loading requires --master-check-timeout",
+            ),
+        ] {
+            assert_eq!(config_error(&[master[0], master[1], arg]), message);
+        }
+        assert_eq!(
+            config_error(&["--master=mymaster=master"]),
+            "--sentinels is required to follow the configured masters"
+        );
+        assert!(config_error(&[]).starts_with("Nothing to do"));
+    }
+
+    #[test]
+    fn masters_and_services_are_configured_once() {
+        assert_eq!(
+            config_error(&[
+                "--sentinels=s:26379",
+                "--master-name=mymaster",
+                "--service-name=master",
+                "--master=mymaster=other",
+            ]),
+            "Master mymaster is configured more than once"
+        );
+        assert_eq!(
+            config_error(&[
+                "--sentinels=s:26379",
+                "--namespace=redis",
+                "--master=one=master",
+                "--master=two=redis/other,master",
+            ]),
+            "Service redis/master is configured more than once"
+        );
+        assert!(options(&[
+            "--sentinels=s:26379",
+            "--master=one=a/master",
+            "--master=two=b/master",
+        ])
+        .into_config()
+        .is_ok());
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::Time, chrono::Utc};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::{
+        controller::Action,
+        finalizer::{self, finalizer},
+        watcher, Controller,
+    },
+    Api, Client, CustomResource, Resource, ResourceExt,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{mpsc::UnboundedSender as Sender, Mutex};
+use tracing::{error, info};
+
+use crate::{
+    format_addr,
+    sentinel::{ConnectionOptions, Fencing, Sentinel, SentinelSet, Targets, Update, Workers},
+    service::{ServiceTarget, DEFAULT_PORT_NAME, FIELD_MANAGER},
+    Error, RedisAddr,
+};
+
+const FINALIZER: &str = "redis.schich.tel/workers";
+const DEFAULT_POLL_INTERVAL_SECONDS: u64 = 30;
+const REQUEUE_INTERVAL: Duration = Duration::from_secs(300);
+const ERROR_REQUEUE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Declares a Service that follows the master of a Sentinel-monitored Redis.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "redis.schich.tel",
+    version = "v1alpha1",
+    kind = "RedisSentinelService",
+    namespaced,
+    status = "RedisSentinelServiceStatus",
+    shortname = "rss",
+    printcolumn = r#"{"name":"Master","type":"string","jsonPath":".status.currentMaster"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct RedisSentinelServiceSpec {
+    /// The `host:port` addresses of the sentinels.
+    pub sentinels: Vec<String>,
+    /// How many sentinels need to agree on a master, defaults to a majority.
+    pub quorum: Option<usize>,
+    /// The name the master is monitored under by the sentinels.
+    pub master_name: String,
+    /// The Service to create in the namespace of this resource.
+    pub service_name: String,
+    /// The Service to create for the healthy replicas, if any.
+    pub replica_service_name: Option<String>,
+    /// The name of the Service port, defaults to `redis`.
+    pub port_name: Option<String>,
+    /// How often the sentinels are asked for the master in addition to listening for switches.
+    #[schemars(range(min = 1))]
+    pub poll_interval_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RedisSentinelServiceStatus {
+    /// The `host:port` address of the master the Service currently points to.
+    pub current_master: Option<String>,
+    /// When the Service was last switched over to a different master.
+    pub last_failover_time: Option<Time>,
+}
+
+/// The spec the workers of each resource were spawned for, and the service they follow.
+type RunningWorkers = HashMap<String, (RedisSentinelServiceSpec, Arc<ServiceTarget>, Workers)>;
+
+struct Context {
+    client: Client,
+    sender: Sender<Update>,
+    /// Where the workers report failures they cannot recover from.
+    fatal: Sender<Error>,
+    options: ConnectionOptions,
+    fencing: Fencing,
+    /// The Services of the statically configured masters, which no resource may take over.
+    static_services: HashSet<String>,
+    workers: Mutex<RunningWorkers>,
+}
+
+fn key(obj: &RedisSentinelService) -> String {
+    format!("{}/{}", obj.namespace().unwrap_or_default(), obj.name_any())
+}
+
+fn service_target(obj: &RedisSentinelService) -> ServiceTarget {
+    let spec = &obj.spec;
+    ServiceTarget {
+        master_name: spec.master_name.clone(),
+        namespace: obj.namespace().unwrap_or_default(),
+        name: spec.service_name.clone(),
+        replica_name: spec.replica_service_name.clone(),
+        port_name: spec
+            .port_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PORT_NAME.to_owned()),
+        owner: obj.controller_owner_ref(&()),
+    }
+}
+
+fn spawn_workers(
+    ctx: &Context,
+    obj: &RedisSentinelService,
+    target: Arc<ServiceTarget>,
+) -> Result<Workers, Error> {
+    let spec = &obj.spec;
+    if spec.poll_interval_seconds == Some(0) {
+        return Err(Error::InvalidConfig(
+            "pollIntervalSeconds must be at least 1".to_owned(),
+        ));
+    }
+    let sentinels = spec
+        .sentinels
+        .iter()
+        .map(|addr| Sentinel::open(addr, &ctx.options))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sentinels = Arc::new(SentinelSet::new(sentinels, spec.quorum)?);
+
+    let targets: Targets = HashMap::from([(spec.master_name.clone(), target)]);
+    let poll_interval = Duration::from_secs(
+        spec.poll_interval_seconds
+            .unwrap_or(DEFAULT_POLL_INTERVAL_SECONDS),
+    );
+
+    Ok(Workers::spawn(
+        sentinels,
+        targets,
+        poll_interval,
+        ctx.fencing,
+        ctx.sender.clone(),
+        ctx.fatal.clone(),
+    ))
+}
+
+/// Fails if a Service of the target is already managed for a static master or another resource,
+/// their workers would keep overwriting each other.
+fn check_unclaimed(
+    ctx: &Context,
+    workers: &RunningWorkers,
+    key: &str,
+    target: &ServiceTarget,
+) -> Result<(), Error> {
+    for service in target.service_keys() {
+        let claimed = ctx.static_services.contains(&service)
+            || workers.iter().any(|(other, (_, other_target, _))| {
+                other != key && other_target.service_keys().contains(&service)
+            });
+        if claimed {
+            return Err(Error::InvalidConfig(format!(
+                "Service {} is already managed for another master",
+                service
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Stops the workers of the resource, if any. Once none of them can send anymore, the main loop
+/// forgets the master they followed, the next workers may follow different sentinels.
+async fn stop_workers(
+    ctx: &Context,
+    workers: &mut RunningWorkers,
+    key: &str,
+) -> Result<bool, Error> {
+    let Some((_, target, stopped)) = workers.remove(key) else {
+        return Ok(false);
+    };
+    stopped.shutdown().await;
+    match ctx.sender.send(Update::Forget { target }) {
+        Ok(()) => Ok(true),
+        Err(_) => Err(Error::ChannelClosed),
+    }
+}
+
+/// Has the main loop delete the Services of the previous target the new one no longer uses,
+/// the owner reference alone would keep them around until the resource is deleted.
+fn retire_services(
+    ctx: &Context,
+    previous: &Arc<ServiceTarget>,
+    target: &ServiceTarget,
+) -> Result<(), Error> {
+    let current = target.service_keys();
+    let previous_names = iter::once(&previous.name).chain(&previous.replica_name);
+    for service_name in previous_names {
+        if current.contains(&format!("{}/{}", previous.namespace, service_name)) {
+            continue;
+        }
+        let update = Update::Retire {
+            target: previous.clone(),
+            service_name: service_name.clone(),
+        };
+        if ctx.sender.send(update).is_err() {
+            return Err(Error::ChannelClosed);
+        }
+    }
+    Ok(())
+}
+
+async fn apply(obj: Arc<RedisSentinelService>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = key(&obj);
+    let mut workers = ctx.workers.lock().await;
+    if let Some((spec, _, _)) = workers.get(&key) {
+        if *spec == obj.spec {
+            return Ok(Action::requeue(REQUEUE_INTERVAL));
+        }
+        info!(resource = key, "Spec changed, restarting the workers");
+    } else {
+        info!(resource = key, "Starting workers");
+    }
+
+    let target = Arc::new(service_target(&obj));
+    check_unclaimed(&ctx, &workers, &key, &target)?;
+    let previous = workers.get(&key).map(|(_, previous, _)| previous.clone());
+    stop_workers(&ctx, &mut workers, &key).await?;
+    if let Some(previous) = previous {
+        retire_services(&ctx, &previous, &target)?;
+    }
+    let new_workers = spawn_workers(&ctx, &obj, target.clone())?;
+    workers.insert(key, (obj.spec.clone(), target, new_workers));
+    Ok(Action::requeue(REQUEUE_INTERVAL))
+}
+
+async fn cleanup(obj: Arc<RedisSentinelService>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = key(&obj);
+    if stop_workers(&ctx, &mut *ctx.workers.lock().await, &key).await? {
+        info!(resource = key, "Stopped workers");
+    }
+    Ok(Action::await_change())
+}
+
+async fn reconcile(
+    obj: Arc<RedisSentinelService>,
+    ctx: Arc<Context>,
+) -> Result<Action, finalizer::Error<Error>> {
+    let api: Api<RedisSentinelService> = Api::namespaced(
+        ctx.client.clone(),
+        obj.namespace().unwrap_or_default().as_str(),
+    );
+    finalizer(&api, FINALIZER, obj, |event| async {
+        match event {
+            finalizer::Event::Apply(obj) => apply(obj, ctx.clone()).await,
+            finalizer::Event::Cleanup(obj) => cleanup(obj, ctx.clone()).await,
+        }
+    })
+    .await
+}
+
+fn error_policy(
+    obj: Arc<RedisSentinelService>,
+    err: &finalizer::Error<Error>,
+    _ctx: Arc<Context>,
+) -> Action {
+    error!(resource = key(&obj), error = %err, "Failed to reconcile");
+    Action::requeue(ERROR_REQUEUE_INTERVAL)
+}
+
+/// Reconciles all [`RedisSentinelService`]s in the cluster, each of them gets its own workers
+/// which send their updates to the given sender and their fatal failures to the other one. The connection options apply to all sentinels.
+pub async fn run_controller(
+    client: Client,
+    sender: Sender<Update>,
+    fatal: Sender<Error>,
+    options: ConnectionOptions,
+    fencing: Fencing,
+    static_services: HashSet<String>,
+) {
+    let api: Api<RedisSentinelService> = Api::all(client.clone());
+    let ctx = Arc::new(Context {
+        client,
+        sender,
+        fatal,
+        options,
+        fencing,
+        static_services,
+        workers: Mutex::new(HashMap::new()),
+    });
+    Controller::new(api, watcher::Config::default())
+        .run(reconcile, error_policy, ctx)
+        .for_each(|_| futures::future::ready(()))
+        .await;
+}
+
+/// Records the master a [`RedisSentinelService`] now points to in its status.
+pub async fn update_status(
+    client: &Client,
+    target: &ServiceTarget,
+    addr: &RedisAddr,
+) -> Result<(), Error> {
+    let Some(owner) = &target.owner else {
+        return Ok(());
+    };
+    if owner.kind != RedisSentinelService::kind(&()) {
+        return Ok(());
+    }
+
+    let api: Api<RedisSentinelService> = Api::namespaced(client.clone(), target.namespace.as_str());
+    let obj = match api.get_status(owner.name.as_str()).await {
+        Ok(obj) => obj,
+        Err(err) => return Err(Error::KubeErr(err)),
+    };
+    let master = format_addr(addr);
+    let previous = obj.status.unwrap_or_default();
+    if previous.current_master.as_ref() == Some(&master) {
+        return Ok(());
+    }
+
+    let status = RedisSentinelServiceStatus {
+        last_failover_time: match previous.current_master {
+            Some(_) => Some(Time(Utc::now())),
+            None => previous.last_failover_time,
+        },
+        current_master: Some(master),
+    };
+    let patch = json!({
+        "apiVersion": RedisSentinelService::api_version(&()),
+        "kind": RedisSentinelService::kind(&()),
+        "status": status,
+    });
+    match api
+        .patch_status(
+            owner.name.as_str(),
+            &PatchParams::apply(FIELD_MANAGER),
+            &Patch::Apply(&patch),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Error::KubeErr(err)),
+    }
+}
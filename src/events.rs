@@ -0,0 +1,203 @@
+use std::sync::{Arc, OnceLock};
+
+use k8s_openapi::api::core::v1::{ObjectReference, Service};
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Api, Client, Resource,
+};
+use tracing::warn;
+
+use crate::{
+    format_addr, leader, service::ServiceTarget, service::FIELD_MANAGER, Error, RedisAddr,
+};
+
+struct Events {
+    client: Client,
+    recorder: Recorder,
+}
+
+static EVENTS: OnceLock<Events> = OnceLock::new();
+
+/// Starts recording events. Events reported before are dropped.
+pub fn init(client: Client, instance: Option<String>) {
+    let reporter = Reporter {
+        controller: FIELD_MANAGER.to_owned(),
+        instance,
+    };
+    let _ = EVENTS.set(Events {
+        recorder: Recorder::new(client.clone(), reporter),
+        client,
+    });
+}
+
+/// Events are recorded against the custom resource the service is created for, if any, and the
+/// service otherwise. `kubectl describe` only shows events matching the uid of the object.
+async fn reference(client: &Client, target: &ServiceTarget) -> ObjectReference {
+    if let Some(owner) = &target.owner {
+        return ObjectReference {
+            api_version: Some(owner.api_version.clone()),
+            kind: Some(owner.kind.clone()),
+            name: Some(owner.name.clone()),
+            namespace: Some(target.namespace.clone()),
+            uid: Some(owner.uid.clone()),
+            ..ObjectReference::default()
+        };
+    }
+    let api: Api<Service> = Api::namespaced(client.clone(), &target.namespace);
+    let uid = match api.get_opt(&target.name).await {
+        Ok(service) => service.and_then(|service| service.meta().uid.clone()),
+        Err(_) => None,
+    };
+    ObjectReference {
+        api_version: Some(Service::api_version(&()).into_owned()),
+        kind: Some(Service::kind(&()).into_owned()),
+        name: Some(target.name.clone()),
+        namespace: Some(target.namespace.clone()),
+        uid,
+        ..ObjectReference::default()
+    }
+}
+
+fn publish(target: &Arc<ServiceTarget>, event: Event) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    // every replica watches the same sentinels, only the leader records events so they are not
+    // duplicated
+    if !leader::is_leading() {
+        return;
+    }
+    let target = target.clone();
+    tokio::spawn(async move {
+        let reference = reference(&events.client, &target).await;
+        if let Err(err) = events.recorder.publish(&event, &reference).await {
+            warn!(
+                master = target.master_name,
+                reason = event.reason,
+                error = %err,
+                "Failed to record event"
+            );
+        }
+    });
+}
+
+/// Records that the service now points to a different master.
+pub fn master_switched(target: &Arc<ServiceTarget>, old: &RedisAddr, new: &RedisAddr) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Normal,
+            reason: "MasterSwitched".to_owned(),
+            note: Some(format!(
+                "Master {} switched from {} to {}",
+                target.master_name,
+                format_addr(old),
+                format_addr(new)
+            )),
+            action: "Failover".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Records that the sentinels started to fail over the master of the target.
+pub fn failover_started(target: &Arc<ServiceTarget>, sentinel: &str) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Normal,
+            reason: "FailoverStarted".to_owned(),
+            note: Some(format!(
+                "Sentinel {} started a failover of {}",
+                sentinel, target.master_name
+            )),
+            action: "Failover".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Warns that the sentinels gave up failing over the master of the target.
+pub fn failover_aborted(target: &Arc<ServiceTarget>, sentinel: &str, reason: &str) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Warning,
+            reason: "FailoverAborted".to_owned(),
+            note: Some(format!(
+                "Sentinel {} aborted the failover of {}: {}",
+                sentinel, target.master_name, reason
+            )),
+            action: "Failover".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Warns that a sentinel monitoring the master of the target may give stale answers.
+pub fn sentinel_tilted(target: &Arc<ServiceTarget>, sentinel: &str) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Warning,
+            reason: "SentinelTilt".to_owned(),
+            note: Some(format!("Sentinel {} entered TILT mode", sentinel)),
+            action: "QuerySentinel".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Warns that the service no longer points to any master.
+pub fn master_fenced(target: &Arc<ServiceTarget>) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Warning,
+            reason: "MasterFenced".to_owned(),
+            note: Some(format!(
+                "Removed the endpoints, no master of {} is agreed on or it is down",
+                target.master_name
+            )),
+            action: "Fence".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Warns that the service keeps pointing to the old master, because the new one failed its check.
+pub fn master_check_failed(target: &Arc<ServiceTarget>, new: &RedisAddr, err: &Error) {
+    publish(
+        target,
+        Event {
+            type_: EventType::Warning,
+            reason: "MasterCheckFailed".to_owned(),
+            note: Some(format!(
+                "Not switching master {} to {}: {}",
+                target.master_name,
+                format_addr(new),
+                err
+            )),
+            action: "CheckMaster".to_owned(),
+            secondary: None,
+        },
+    );
+}
+
+/// Warns that a sentinel could not be asked about the master of the target.
+pub fn sentinel_failed(target: &Arc<ServiceTarget>, sentinel: &str, err: &Error) {
+    let reason = match err {
+        Error::InvalidResponse(_) => "InvalidSentinelResponse",
+        _ => "SentinelUnreachable",
+    };
+    publish(
+        target,
+        Event {
+            type_: EventType::Warning,
+            reason: reason.to_owned(),
+            note: Some(format!("Sentinel {}: {}", sentinel, err)),
+            action: "QuerySentinel".to_owned(),
+            secondary: None,
+        },
+    );
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::leader;
+
+/// How long the main loop may go without a heartbeat before the process counts as stuck.
+const MAIN_LOOP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a supervisor may go without checking its workers before they count as dead.
+const SUPERVISOR_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many poll intervals the sentinels may stay unreachable before the process is not ready.
+const READY_POLL_INTERVALS: u32 = 3;
+
+static MAIN_LOOP: Mutex<Option<Instant>> = Mutex::new(None);
+static WORKER_GROUPS: Mutex<Vec<Weak<WorkerHealth>>> = Mutex::new(Vec::new());
+
+/// A poisoned lock only means another thread panicked while reporting, the timestamps inside
+/// are still good enough for the probes.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What the workers of one group report about themselves, the group is forgotten once this is
+/// dropped.
+pub struct WorkerHealth {
+    poll_interval: Duration,
+    supervised_at: Mutex<Instant>,
+    sentinel_reached_at: Mutex<Option<Instant>>,
+    /// The services of the group that were never materialized yet.
+    pending_services: Mutex<HashSet<String>>,
+}
+
+impl WorkerHealth {
+    pub fn register(poll_interval: Duration, services: HashSet<String>) -> Arc<Self> {
+        let health = Arc::new(WorkerHealth {
+            poll_interval,
+            supervised_at: Mutex::new(Instant::now()),
+            sentinel_reached_at: Mutex::new(None),
+            pending_services: Mutex::new(services),
+        });
+        let mut groups = lock(&WORKER_GROUPS);
+        groups.retain(|group| group.strong_count() > 0);
+        groups.push(Arc::downgrade(&health));
+        health
+    }
+
+    /// To be called by the supervisor whenever it checked on the workers.
+    pub fn supervised(&self) {
+        *lock(&self.supervised_at) = Instant::now();
+    }
+
+    /// To be called whenever a sentinel answered.
+    pub fn sentinel_reached(&self) {
+        *lock(&self.sentinel_reached_at) = Some(Instant::now());
+    }
+
+    fn check_alive(&self) -> Result<(), String> {
+        let elapsed = lock(&self.supervised_at).elapsed();
+        if elapsed > SUPERVISOR_TIMEOUT {
+            return Err(format!("Workers were not supervised for {:?}", elapsed));
+        }
+        Ok(())
+    }
+
+    fn check_ready(&self) -> Result<(), String> {
+        let pending = lock(&self.pending_services);
+        // followers do not materialize anything, so they cannot wait for their services
+        if !pending.is_empty() && leader::is_leading() {
+            return Err(format!("Services not materialized yet: {:?}", pending));
+        }
+        match *lock(&self.sentinel_reached_at) {
+            Some(reached) if reached.elapsed() <= self.poll_interval * READY_POLL_INTERVALS => {
+                Ok(())
+            }
+            Some(reached) => Err(format!(
+                "No sentinel was reached for {:?}",
+                reached.elapsed()
+            )),
+            None => Err("No sentinel was reached yet".to_owned()),
+        }
+    }
+}
+
+fn worker_groups() -> Vec<Arc<WorkerHealth>> {
+    lock(&WORKER_GROUPS)
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// To be called by the main loop whenever it is ready to handle the next update.
+pub fn main_loop_alive() {
+    *lock(&MAIN_LOOP) = Some(Instant::now());
+}
+
+/// Records that the service with the given `<namespace>/<name>` key was materialized.
+pub fn service_materialized(key: &str) {
+    for group in worker_groups() {
+        lock(&group.pending_services).remove(key);
+    }
+}
+
+/// The process is alive as long as the main loop keeps handling updates and the workers are
+/// supervised, a dead worker is restarted by its supervisor.
+pub fn check_alive() -> Result<(), String> {
+    if let Some(beat) = *lock(&MAIN_LOOP) {
+        if beat.elapsed() > MAIN_LOOP_TIMEOUT {
+            return Err(format!("Main loop is stuck for {:?}", beat.elapsed()));
+        }
+    }
+    worker_groups()
+        .iter()
+        .try_for_each(|group| group.check_alive())
+}
+
+/// The process is ready once the services of all workers were materialized, unless it is a
+/// follower, and their sentinels were reached recently.
+pub fn check_ready() -> Result<(), String> {
+    if lock(&MAIN_LOOP).is_none() {
+        return Err("Main loop did not start yet".to_owned());
+    }
+    check_alive()?;
+    worker_groups()
+        .iter()
+        .try_for_each(|group| group.check_ready())
+}
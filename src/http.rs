@@ -0,0 +1,42 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tokio::net::TcpListener;
+
+use crate::{health, metrics::encode_metrics};
+
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+async fn metrics() -> Response {
+    match encode_metrics() {
+        Ok(body) => ([(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)], body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+fn probe(check: Result<(), String>) -> Response {
+    match check {
+        Ok(()) => (StatusCode::OK, "ok").into_response(),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    }
+}
+
+async fn healthz() -> Response {
+    probe(health::check_alive())
+}
+
+async fn readyz() -> Response {
+    probe(health::check_ready())
+}
+
+/// Serves the metrics and the probes until the listener fails.
+pub async fn serve(listener: TcpListener) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    axum::serve(listener, app).await
+}
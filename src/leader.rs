@@ -0,0 +1,236 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+    chrono::Utc,
+};
+use kube::{
+    api::{ObjectMeta, PostParams},
+    Api, Client,
+};
+use tokio::{
+    sync::{oneshot, watch},
+    time::{sleep, Instant},
+};
+use tracing::{info, warn};
+
+use crate::Error;
+
+/// How many attempts to acquire or renew the lease are made per lease duration.
+const ATTEMPTS_PER_LEASE_DURATION: u32 = 5;
+
+/// Whether this replica holds the lease, without leader election it always does.
+static LEADING: AtomicBool = AtomicBool::new(true);
+
+pub fn set_leading(leading: bool) {
+    LEADING.store(leading, Ordering::Relaxed);
+}
+
+pub fn is_leading() -> bool {
+    LEADING.load(Ordering::Relaxed)
+}
+
+/// Competes with the other replicas for a Lease, only its holder is supposed to write.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+    /// The holder and renew time of the lease when last seen and when that changed. Comparing
+    /// against our own clock instead of the renew time keeps the election safe from clock skew.
+    observed: Option<(Option<String>, Option<MicroTime>, Instant)>,
+}
+
+/// Whether the error is caused by another replica writing the lease concurrently.
+fn is_conflict(err: &kube::Error) -> bool {
+    matches!(err, kube::Error::Api(response) if response.code == 409)
+}
+
+impl LeaderElector {
+    pub fn new(
+        client: Client,
+        namespace: &str,
+        name: String,
+        identity: String,
+        lease_duration: Duration,
+    ) -> Self {
+        LeaderElector {
+            api: Api::namespaced(client, namespace),
+            name,
+            identity,
+            lease_duration,
+            observed: None,
+        }
+    }
+
+    fn lease_duration_seconds(&self) -> i32 {
+        self.lease_duration.as_secs().try_into().unwrap_or(i32::MAX)
+    }
+
+    /// Whether the lease was held by someone else without being renewed for a whole lease
+    /// duration, as far as we have seen. The duration is the one the holder wrote into the
+    /// lease, it may be configured differently than ours.
+    fn expired(&mut self, spec: &LeaseSpec) -> bool {
+        let lease_duration = match spec.lease_duration_seconds.map(u64::try_from) {
+            Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+            _ => self.lease_duration,
+        };
+        let seen = (spec.holder_identity.clone(), spec.renew_time.clone());
+        match &self.observed {
+            Some((holder, renew_time, since)) if (holder, renew_time) == (&seen.0, &seen.1) => {
+                spec.holder_identity.is_none() || since.elapsed() > lease_duration
+            }
+            _ => {
+                self.observed = Some((seen.0, seen.1, Instant::now()));
+                spec.holder_identity.is_none()
+            }
+        }
+    }
+
+    /// Acquires or renews the lease once, returns whether we hold it afterwards.
+    async fn try_acquire_or_renew(&mut self) -> Result<bool, Error> {
+        let now = MicroTime(Utc::now());
+        let existing = match self.api.get_opt(&self.name).await {
+            Ok(existing) => existing,
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+
+        let Some(mut lease) = existing else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.clone()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(self.lease_duration_seconds()),
+                    acquire_time: Some(now.clone()),
+                    renew_time: Some(now),
+                    lease_transitions: Some(0),
+                    ..LeaseSpec::default()
+                }),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(err) if is_conflict(&err) => Ok(false),
+                Err(err) => Err(Error::KubeErr(err)),
+            };
+        };
+
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            if !self.expired(spec) {
+                return Ok(false);
+            }
+            info!(
+                lease = self.name,
+                previous_holder = ?spec.holder_identity,
+                "Taking over the lease"
+            );
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(now.clone());
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+        spec.renew_time = Some(now);
+        spec.lease_duration_seconds = Some(self.lease_duration_seconds());
+
+        // the resource version of the lease we read makes this fail if anyone else wrote it
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) if is_conflict(&err) => Ok(false),
+            Err(err) => Err(Error::KubeErr(err)),
+        }
+    }
+
+    /// Gives up the lease if we hold it, so another replica can take over right away instead of
+    /// waiting for it to expire.
+    async fn release(&self) -> Result<(), Error> {
+        let mut lease = match self.api.get_opt(&self.name).await {
+            Ok(Some(lease)) => lease,
+            Ok(None) => return Ok(()),
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+        let Some(spec) = lease.spec.as_mut() else {
+            return Ok(());
+        };
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.renew_time = Some(MicroTime(Utc::now()));
+
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) if is_conflict(&err) => Ok(()),
+            Err(err) => Err(Error::KubeErr(err)),
+        }
+    }
+
+    /// Keeps competing for the lease and publishes whether we are the leader. Leadership is given
+    /// up before the lease could expire when it cannot be renewed, so there are never two
+    /// leaders at the same time. Once stopped, the lease is released if we hold it.
+    pub async fn run(mut self, sender: watch::Sender<bool>, mut stop: oneshot::Receiver<()>) {
+        let retry_interval = self.lease_duration / ATTEMPTS_PER_LEASE_DURATION;
+        let mut renewed_at: Option<Instant> = None;
+        loop {
+            let attempt_started = Instant::now();
+            match self.try_acquire_or_renew().await {
+                Ok(true) => renewed_at = Some(attempt_started),
+                Ok(false) => renewed_at = None,
+                Err(err) => warn!(
+                    lease = self.name,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to acquire or renew the lease"
+                ),
+            }
+
+            let leading =
+                renewed_at.is_some_and(|at| at.elapsed() + retry_interval < self.lease_duration);
+            sender.send_if_modified(|previous| {
+                let changed = *previous != leading;
+                if changed && leading {
+                    info!(
+                        lease = self.name,
+                        identity = self.identity,
+                        "Acquired the lease"
+                    );
+                } else if changed {
+                    warn!(lease = self.name, "Lost the lease");
+                }
+                *previous = leading;
+                changed
+            });
+            tokio::select! {
+                _ = sleep(retry_interval) => {}
+                _ = &mut stop => break,
+            }
+        }
+
+        sender.send_replace(false);
+        if renewed_at.is_none() {
+            return;
+        }
+        match self.release().await {
+            Ok(()) => info!(lease = self.name, "Released the lease"),
+            Err(err) => warn!(
+                lease = self.name,
+                error.kind = err.kind(),
+                error = %err,
+                "Failed to release the lease"
+            ),
+        }
+    }
+}
@@ -1,30 +1,58 @@
+mod backoff;
+mod config;
+mod crd;
+mod events;
+mod health;
+mod http;
+mod leader;
+mod metrics;
+mod parser;
+mod sentinel;
+mod service;
+
 use std::{
-    env,
+    collections::{HashMap, HashSet},
     fmt::Display,
-    net::SocketAddr,
+    future,
+    net::IpAddr,
     process::ExitCode,
-    sync::{
-        mpsc::{self, Sender},
-        Arc,
-    },
-    thread::{self, JoinHandle},
+    sync::Arc,
     time::Duration,
 };
 
-use redis::{cmd, Client, Cmd, Connection, ControlFlow, PubSubCommands, RedisError};
-
-use std::net::ToSocketAddrs;
-
-fn get_master_from_sentinel_cmd(name: &str) -> Cmd {
-    let mut cmd = cmd("SENTINEL");
-    cmd.arg("get-master-addr-by-name").arg(name);
-    return cmd;
-}
+use clap::Parser;
+use config::{LogFormat, Options};
+use crd::{run_controller, update_status, RedisSentinelService};
+use kube::CustomResourceExt;
+use leader::LeaderElector;
+use parser::SentinelEvent;
+use redis::RedisError;
+use sentinel::{wait_for_master, Sentinel, SentinelSet, Targets, Update, Workers};
+use service::{Materializer, ServiceTarget};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot, watch},
+    task::{self, AbortHandle, JoinError, JoinHandle, JoinSet},
+    time::{interval, timeout_at, Instant},
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug)]
 enum Error {
     RedisErr(RedisError),
     InvalidResponse(String),
+    ResolveErr(std::io::Error),
+    KubeErr(kube::Error),
+    NotOwned(String),
+    NoQuorum(String),
+    /// Too few sentinels answered to tell whether they agree on a master.
+    Unreachable(String),
+    InvalidConfig(String),
+    MasterDown(String),
+    /// The workers of a master kept dying and were given up on.
+    WorkersFailed(String),
+    ChannelClosed,
 }
 
 impl Display for Error {
@@ -32,172 +60,699 @@ impl Display for Error {
         match self {
             Error::RedisErr(err) => write!(f, "RedisError({})", err),
             Error::InvalidResponse(err) => write!(f, "InvalidResponse({})", err),
+            Error::ResolveErr(err) => write!(f, "ResolveError({})", err),
+            Error::KubeErr(err) => write!(f, "KubeError({})", err),
+            Error::NotOwned(err) => write!(f, "NotOwned({})", err),
+            Error::NoQuorum(err) => write!(f, "NoQuorum({})", err),
+            Error::Unreachable(err) => write!(f, "Unreachable({})", err),
+            Error::InvalidConfig(err) => write!(f, "InvalidConfig({})", err),
+            Error::MasterDown(err) => write!(f, "MasterDown({})", err),
+            Error::WorkersFailed(err) => write!(f, "WorkersFailed({})", err),
+            Error::ChannelClosed => write!(f, "ChannelClosed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The variant of the error, to be logged as a field that alerts can match on.
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::RedisErr(_) => "RedisError",
+            Error::InvalidResponse(_) => "InvalidResponse",
+            Error::ResolveErr(_) => "ResolveError",
+            Error::KubeErr(_) => "KubeError",
+            Error::NotOwned(_) => "NotOwned",
+            Error::NoQuorum(_) => "NoQuorum",
+            Error::Unreachable(_) => "Unreachable",
+            Error::InvalidConfig(_) => "InvalidConfig",
+            Error::MasterDown(_) => "MasterDown",
+            Error::WorkersFailed(_) => "WorkersFailed",
+            Error::ChannelClosed => "ChannelClosed",
         }
     }
 }
 
 type RedisAddr = (String, u16);
 
-fn get_master_from_sentinel(
-    connection: &mut Connection,
-    master_name: &str,
-) -> Result<RedisAddr, Error> {
-    let response = match get_master_from_sentinel_cmd(master_name).query::<Vec<String>>(connection)
-    {
-        Ok(response) => response,
-        Err(redis_err) => return Err(Error::RedisErr(redis_err)),
-    };
+/// Brackets IPv6 hosts, so the port stays distinguishable.
+fn format_addr(addr: &RedisAddr) -> String {
+    match addr.0.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, addr.1),
+        _ => format!("{}:{}", addr.0, addr.1),
+    }
+}
 
-    if response.len() != 2 {
-        return Err(Error::InvalidResponse(
-            "Response did not have exactly 2 elements!".to_owned(),
-        ));
+/// Strips the brackets of IPv6 hosts and writes IP addresses in their canonical form, so the
+/// same address announced differently by two sentinels still compares equal.
+fn normalize_host(host: &str) -> String {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    match unbracketed.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_owned(),
     }
+}
 
-    let host: String = response[0].to_owned();
-    let port: u16 = match response[1].parse() {
-        Ok(p) => p,
-        Err(err) => return Err(Error::InvalidResponse(format!("Port is invalid: {}", err))),
-    };
+/// How often the main loop proves it is not stuck while there are no updates.
+const MAIN_LOOP_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 
-    return Ok((host, port));
+/// Waits for the controller task to end, which it only does when it failed.
+async fn controller_exit(controller: &mut Option<JoinHandle<()>>) -> Result<(), JoinError> {
+    match controller {
+        Some(controller) => controller.await,
+        None => future::pending().await,
+    }
 }
 
-fn listen_for_master_switches(
-    client: Arc<Client>,
-    sender: Sender<RedisAddr>,
-    master_name: &str,
-) -> JoinHandle<()> {
-    let master_name = master_name.to_string();
-    return thread::spawn(move || loop {
-        let mut connection = match client.get_connection() {
-            Ok(c) => c,
-            Err(err) => {
-                eprintln!("Failed to connect: {}", err);
-                continue;
-            }
-        };
-        let topic = "+switch-master";
-        let subscribe_result = connection.subscribe::<_, _, ()>(topic, |msg| {
-            let value: String = msg.get_payload().unwrap();
-            let segments: Vec<&str> = value
-                .as_str()
-                .split_ascii_whitespace()
-                .into_iter()
-                .collect();
-            if segments.len() < 5 {
-                eprintln!("Received invalid switch-master event: {:?}", segments);
-                return ControlFlow::Continue;
-            }
-            let affected_master = segments[0];
-            if master_name.as_str() != affected_master {
-                println!(
-                    "Master changed for {}, we are not interested in that...",
-                    affected_master
-                );
-                return ControlFlow::Continue;
-            }
-            let host = segments[3].to_owned();
-            let port: u16 = segments[4].parse().unwrap();
-            sender.send((host, port)).unwrap();
-            ControlFlow::Continue
-        });
+/// Waits for the leadership to change, without leader election it never does.
+async fn leadership_change(
+    leader: &mut Option<watch::Receiver<bool>>,
+) -> Result<bool, watch::error::RecvError> {
+    match leader {
+        Some(leader) => {
+            leader.changed().await?;
+            Ok(*leader.borrow_and_update())
+        }
+        None => future::pending().await,
+    }
+}
 
-        if let Err(err) = subscribe_result {
-            eprintln!("Failed to subscribe to topic {}: {}", topic, err);
-            continue;
+/// Removes the endpoints an update pointed the service to.
+async fn clear_update(materializer: &Materializer, update: &Update) -> Result<(), Error> {
+    match update {
+        Update::Master { target, .. } | Update::Fenced { target } => {
+            materializer.clear_service(target).await
         }
-    });
+        Update::Replicas { target, .. } => materializer.clear_replicas(target).await,
+        Update::Event { .. } | Update::Forget { .. } | Update::Retire { .. } => Ok(()),
+    }
 }
 
-fn poll_master_address(
-    client: Arc<Client>,
-    sender: Sender<RedisAddr>,
-    master_name: &str,
-    poll_interval: &Duration,
-) -> JoinHandle<()> {
-    let master_name = master_name.to_string();
-    let poll_interval = *poll_interval;
-    return thread::spawn(move || loop {
-        let mut connection = match client.get_connection() {
-            Ok(c) => c,
-            Err(err) => {
-                eprintln!("Failed to connect: {}", err);
-                continue;
+async fn apply_update(
+    materializer: &Materializer,
+    kube_client: &kube::Client,
+    update: &Update,
+) -> Result<(), Error> {
+    match update {
+        Update::Master {
+            target,
+            addr,
+            epoch,
+        } => {
+            info!(
+                master = target.master_name,
+                service = target.key(),
+                new_master = format_addr(addr),
+                epoch,
+                "Received new master"
+            );
+            materializer.materialize_service(target, addr).await?;
+            update_status(kube_client, target, addr).await
+        }
+        Update::Replicas { target, addrs } => {
+            info!(
+                master = target.master_name,
+                service = target.replica_name,
+                ?addrs,
+                "Received replicas"
+            );
+            materializer.materialize_replicas(target, addrs).await
+        }
+        Update::Fenced { target } => {
+            warn!(
+                master = target.master_name,
+                service = target.key(),
+                "Removing the endpoints until a master is agreed on"
+            );
+            materializer.clear_service(target).await
+        }
+        Update::Retire {
+            target,
+            service_name,
+        } => {
+            info!(
+                master = target.master_name,
+                service = update.key(),
+                "Deleting a Service no longer in use"
+            );
+            materializer.delete_service(target, service_name).await
+        }
+        Update::Event { .. } | Update::Forget { .. } => Ok(()),
+    }
+}
+
+/// Reacts to an event of a sentinel.
+fn observe_event(target: &Arc<ServiceTarget>, sentinel: &str, event: &SentinelEvent) {
+    match event {
+        SentinelEvent::TryFailover { .. } => {
+            info!(
+                master = target.master_name,
+                sentinel, "Sentinels started a failover"
+            );
+            events::failover_started(target, sentinel);
+        }
+        SentinelEvent::FailoverState { state, .. } => {
+            info!(
+                master = target.master_name,
+                sentinel, state, "Failover in progress"
+            )
+        }
+        SentinelEvent::FailoverEnd { .. } => {
+            info!(master = target.master_name, sentinel, "Failover ended")
+        }
+        SentinelEvent::FailoverAbort { reason, .. } => {
+            warn!(
+                master = target.master_name,
+                sentinel, reason, "Failover aborted"
+            );
+            events::failover_aborted(target, sentinel, reason);
+        }
+        SentinelEvent::Tilt { active: true } => {
+            warn!(
+                master = target.master_name,
+                sentinel, "Sentinel entered TILT mode"
+            );
+            events::sentinel_tilted(target, sentinel);
+        }
+        SentinelEvent::Tilt { active: false } => {
+            info!(
+                master = target.master_name,
+                sentinel, "Sentinel left TILT mode"
+            )
+        }
+        SentinelEvent::ObjectivelyDown { instance, down } => {
+            if *down {
+                warn!(
+                    master = target.master_name,
+                    sentinel,
+                    instance = format_addr(&instance.addr),
+                    role = instance.role,
+                    "Instance is objectively down"
+                )
+            } else {
+                info!(
+                    master = target.master_name,
+                    sentinel,
+                    instance = format_addr(&instance.addr),
+                    role = instance.role,
+                    "Instance is no longer objectively down"
+                )
             }
-        };
-        match get_master_from_sentinel(&mut connection, master_name.as_str()) {
-            Ok(master) => {
-                sender.send(master).unwrap();
+        }
+        event => debug!(
+            master = target.master_name,
+            sentinel,
+            ?event,
+            "Sentinel event"
+        ),
+    }
+}
+
+/// What the main loop keeps track of for each service.
+#[derive(Default)]
+struct Tracked {
+    /// The last update successfully applied to each service.
+    applied: HashMap<String, Update>,
+    /// The last master each service pointed to.
+    masters: HashMap<String, RedisAddr>,
+    /// The latest update for each service while following, to be applied when taking over.
+    followed: HashMap<String, Update>,
+    /// The master updates whose new master is still being checked.
+    checking: HashMap<String, (Update, AbortHandle)>,
+}
+
+impl Tracked {
+    /// Forgets everything about the service of the target, whose workers were replaced or
+    /// stopped.
+    fn forget(&mut self, pending: &mut HashMap<String, Update>, target: &ServiceTarget) {
+        let key = target.key();
+        if let Some(Update::Master { target, addr, .. }) = self.applied.remove(&key) {
+            metrics::forget_master(&target, &addr);
+        }
+        self.masters.remove(&key);
+        self.followed.remove(&key);
+        if let Some((_, check)) = self.checking.remove(&key) {
+            check.abort();
+        }
+        pending.remove(&key);
+    }
+
+    /// Takes the update whose check finished, unless the check was superseded meanwhile.
+    fn finish_check(&mut self, key: &str, id: task::Id) -> Option<Update> {
+        match self.checking.get(key) {
+            Some((_, check)) if check.id() == id => {
+                self.checking.remove(key).map(|(update, _)| update)
             }
-            Err(err) => {
-                eprintln!("Failed to get initial master: {}", err);
+            _ => None,
+        }
+    }
+
+    /// Applies the update and records what the service points to now.
+    async fn apply(
+        &mut self,
+        materializer: &Materializer,
+        kube_client: &kube::Client,
+        key: String,
+        update: Update,
+    ) {
+        if let Err(err) = apply_update(materializer, kube_client, &update).await {
+            error!(
+                service = key,
+                error.kind = err.kind(),
+                error = %err,
+                "Failed to apply the update"
+            );
+            return;
+        }
+        if let Some(Update::Master {
+            target: previous_target,
+            addr: previous_addr,
+            ..
+        }) = self.applied.get(&key)
+        {
+            metrics::forget_master(previous_target, previous_addr);
+        }
+        match &update {
+            Update::Master { target, addr, .. } => {
+                // compared to the last master rather than the last update, so a failover is
+                // noticed even when the service was fenced in between
+                if let Some(previous_addr) = self.masters.insert(key.clone(), addr.clone()) {
+                    if previous_addr != *addr {
+                        info!(
+                            master = target.master_name,
+                            service = key,
+                            old_master = format_addr(&previous_addr),
+                            new_master = format_addr(addr),
+                            "Master switched"
+                        );
+                        events::master_switched(target, &previous_addr, addr);
+                    }
+                }
+                metrics::record_master(target, addr);
+                health::service_materialized(&key);
             }
-        };
-        thread::sleep(poll_interval);
-    });
+            Update::Fenced { target } => events::master_fenced(target),
+            Update::Retire { .. } => {
+                // nothing is left to clear or to apply again when taking over
+                self.applied.remove(&key);
+                return;
+            }
+            Update::Replicas { .. } | Update::Event { .. } | Update::Forget { .. } => {}
+        }
+        self.applied.insert(key, update);
+    }
+
+    /// Collects an update to be applied, events are reacted to right away instead.
+    fn collect(&mut self, pending: &mut HashMap<String, Update>, update: Update) {
+        match update {
+            Update::Event {
+                target,
+                sentinel,
+                event,
+            } => observe_event(&target, &sentinel, &event),
+            Update::Forget { target } => self.forget(pending, &target),
+            // the workers dropped stale masters already, so the latest update is the one to apply
+            update => {
+                pending.insert(update.key(), update);
+            }
+        }
+    }
 }
 
-fn materialize_service(addr: &RedisAddr) {
-    let socket_addrs: Vec<SocketAddr> = match addr.to_socket_addrs() {
-        Ok(addrs) => addrs.collect(),
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = Options::parse();
+    if options.print_crd {
+        match serde_json::to_string_pretty(&RedisSentinelService::crd()) {
+            Ok(crd) => {
+                println!("{}", crd);
+                return ExitCode::SUCCESS;
+            }
+            Err(err) => {
+                eprintln!("Failed to serialize the CRD: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let config = match options.into_config() {
+        Ok(config) => config,
         Err(err) => {
-            eprintln!("Failed to resolve the address: {}", err);
-            return;
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
         }
     };
-
-    for addr in socket_addrs {
-        println!("Resolved: {}", addr);
+    let subscriber = tracing_subscriber::fmt().with_env_filter(config.log_filter);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
     }
-}
 
-fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Wrong arguments!");
-        eprintln!(
-            "Usage: {} <sentinal host:port> <master name> <poll interval secs>",
-            args[0]
-        );
-        return ExitCode::FAILURE;
-    }
-    let sentinel_addr = args[1].clone();
-    let master_name = args[2].clone();
-    let poll_interval = Duration::from_secs(args[3].parse().unwrap());
-    let client = Arc::new(redis::Client::open(format!("redis://{}/", sentinel_addr)).unwrap());
-    let mut connection = client.get_connection().unwrap();
-    let initial_master = match get_master_from_sentinel(&mut connection, master_name.as_str()) {
-        Ok(m) => m,
+    let listener = match TcpListener::bind(config.listen_address).await {
+        Ok(listener) => listener,
         Err(err) => {
-            eprintln!("Failed to get initial master: {}", err);
+            error!(address = %config.listen_address, error = %err, "Failed to listen");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut server = tokio::spawn(http::serve(listener));
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(err), _) | (_, Err(err)) => {
+            error!(error = %err, "Failed to handle signals");
             return ExitCode::FAILURE;
         }
     };
 
-    println!("Master: {:?}", initial_master);
-    materialize_service(&initial_master);
+    let kube_client = match kube::Client::try_default().await {
+        Ok(c) => c,
+        Err(err) => {
+            error!(error = %err, "Failed to create the kubernetes client");
+            return ExitCode::FAILURE;
+        }
+    };
+    events::init(kube_client.clone(), config.identity);
+    let materializer = Materializer::new(
+        kube_client.clone(),
+        config.force,
+        config.resolution,
+        config.ip_families,
+    );
+    let (tx, mut rx) = mpsc::unbounded_channel::<Update>();
+    // the workers report here when they give up, the process then shuts down like on a signal
+    let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel::<Error>();
 
-    let (tx, rx) = mpsc::channel::<RedisAddr>();
+    // keeps the workers of the statically configured masters running
+    let mut static_workers: Option<Workers> = None;
+    let mut static_services = HashSet::new();
+    if !config.masters.is_empty() {
+        let targets: Targets = config
+            .masters
+            .into_iter()
+            .map(|mapping| mapping.into_target(kube_client.default_namespace()))
+            .collect();
+        for service in targets.values().flat_map(|target| target.service_keys()) {
+            // only caught here when one mapping names the default namespace and another omits it
+            if !static_services.insert(service.clone()) {
+                error!(service, "Service is configured more than once");
+                return ExitCode::FAILURE;
+            }
+        }
 
-    let _ = listen_for_master_switches(client.clone(), tx.clone(), master_name.as_str());
-    let _ = poll_master_address(
-        client.clone(),
-        tx.clone(),
-        master_name.as_str(),
-        &poll_interval,
-    );
+        let sentinels = match config
+            .sentinels
+            .iter()
+            .map(|addr| Sentinel::open(addr, &config.connection))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|sentinels| SentinelSet::new(sentinels, config.quorum))
+        {
+            Ok(sentinels) => Arc::new(sentinels),
+            Err(err) => {
+                error!(error.kind = err.kind(), error = %err, "Failed to set up the sentinels");
+                return ExitCode::FAILURE;
+            }
+        };
+
+        for (master_name, target) in &targets {
+            let initial_master = match sentinels.query_master(master_name.as_str()).await {
+                Ok(m) => m,
+                Err(err) => {
+                    warn!(
+                        master = master_name,
+                        error.kind = err.kind(),
+                        error = %err,
+                        "Failed to get the initial master"
+                    );
+                    continue;
+                }
+            };
+
+            let (addr, epoch) = initial_master;
+            info!(
+                master = master_name,
+                address = format_addr(&addr),
+                epoch,
+                "Found the initial master"
+            );
+            let update = Update::Master {
+                target: target.clone(),
+                addr,
+                epoch,
+            };
+            if tx.send(update).is_err() {
+                error!(
+                    master = master_name,
+                    error.kind = Error::ChannelClosed.kind(),
+                    "Failed to send the initial master"
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+
+        static_workers = Some(Workers::spawn(
+            sentinels,
+            targets,
+            config.poll_interval,
+            config.fencing,
+            tx.clone(),
+            fatal_tx.clone(),
+        ));
+    }
 
+    let mut controller = if config.crd {
+        Some(tokio::spawn(run_controller(
+            kube_client.clone(),
+            tx.clone(),
+            fatal_tx.clone(),
+            config.connection,
+            config.fencing,
+            static_services,
+        )))
+    } else {
+        None
+    };
+
+    // the elector is stopped through the sender, so it can release the lease
+    let mut election: Option<(oneshot::Sender<()>, JoinHandle<()>)> = None;
+    let mut leader = config.leader_election.map(|election_config| {
+        let (sender, receiver) = watch::channel(false);
+        let elector = LeaderElector::new(
+            kube_client.clone(),
+            election_config
+                .lease_namespace
+                .as_deref()
+                .unwrap_or(kube_client.default_namespace()),
+            election_config.lease_name,
+            election_config.identity,
+            election_config.lease_duration,
+        );
+        let (stop, stopped) = oneshot::channel();
+        election = Some((stop, tokio::spawn(elector.run(sender, stopped))));
+        receiver
+    });
+    let mut leading = leader.is_none();
+    leader::set_leading(leading);
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut tracked = Tracked::default();
+    // the checks of new masters, by the key of their service
+    let mut checks: JoinSet<(String, Result<(), Error>)> = JoinSet::new();
+    let mut heartbeat = interval(MAIN_LOOP_HEARTBEAT_INTERVAL);
     loop {
-        let addr = match rx.recv() {
-            Ok(addr) => addr,
-            Err(err) => {
-                eprintln!("Failed to receive: {}", err);
+        health::main_loop_alive();
+        let mut pending: HashMap<String, Update> = HashMap::new();
+        tokio::select! {
+            update = rx.recv() => match update {
+                Some(update) => tracked.collect(&mut pending, update),
+                None => {
+                    error!("Failed to receive: all senders are gone");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ = heartbeat.tick() => continue,
+            Some(result) = checks.join_next_with_id(), if !checks.is_empty() => {
+                // an aborted check was superseded by a later update
+                let Ok((id, (key, result))) = result else {
+                    continue;
+                };
+                let Some(update) = tracked.finish_check(&key, id) else {
+                    continue;
+                };
+                match (result, &update) {
+                    (Ok(()), _) => tracked.apply(&materializer, &kube_client, key, update).await,
+                    (Err(err), Update::Master { target, addr, .. }) => {
+                        error!(
+                            master = target.master_name,
+                            service = key,
+                            new_master = format_addr(addr),
+                            error.kind = err.kind(),
+                            error = %err,
+                            "New master failed its check, keeping the previous one"
+                        );
+                        events::master_check_failed(target, addr, &err);
+                    }
+                    (Err(_), _) => {}
+                }
                 continue;
             }
+            leadership = leadership_change(&mut leader) => {
+                leading = match leadership {
+                    Ok(leading) => leading,
+                    Err(_) => {
+                        error!("The leader election stopped");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                leader::set_leading(leading);
+                if !leading {
+                    warn!("Lost the leadership, no longer applying updates");
+                    // the next leader may change anything, so everything is applied again when
+                    // taking over
+                    for update in tracked.applied.values() {
+                        if let Update::Master { target, addr, .. } = update {
+                            metrics::forget_master(target, addr);
+                        }
+                    }
+                    tracked.applied.clear();
+                    tracked.masters.clear();
+                    tracked.checking.clear();
+                    checks.abort_all();
+                    continue;
+                }
+                info!("Became the leader, applying the latest updates");
+                pending = std::mem::take(&mut tracked.followed);
+            }
+            result = controller_exit(&mut controller) => {
+                error!(?result, "The custom resource controller stopped");
+                return ExitCode::FAILURE;
+            }
+            result = &mut server => {
+                error!(?result, "The HTTP server stopped");
+                return ExitCode::FAILURE;
+            }
+            Some(err) = fatal_rx.recv() => {
+                error!(error.kind = err.kind(), error = %err, "Workers failed, shutting down");
+                exit_code = ExitCode::FAILURE;
+                break;
+            }
+            // updates are only applied outside of this select, so no write is interrupted
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
         };
+        // collects a burst of updates and only applies the latest one per service
+        if let Some(debounce) = config.debounce {
+            let deadline = Instant::now() + debounce;
+            while let Ok(Some(update)) = timeout_at(deadline, rx.recv()).await {
+                tracked.collect(&mut pending, update);
+            }
+        }
+        if !leading {
+            for update in pending.into_values() {
+                tracked.followed.insert(update.key(), update);
+            }
+            continue;
+        }
+
+        for (key, update) in pending {
+            if let Some((checked, check)) = tracked.checking.get(&key) {
+                if *checked == update {
+                    continue;
+                }
+                // superseded before its check finished
+                check.abort();
+                tracked.checking.remove(&key);
+            }
+            if tracked.applied.get(&key) == Some(&update) {
+                // workers spawned anew for a changed spec wait for their service all the same
+                if let Update::Master { .. } = update {
+                    health::service_materialized(&key);
+                }
+                continue;
+            }
+            if let (Some(check), Update::Master { target, addr, .. }) =
+                (&config.master_check, &update)
+            {
+                let previous_addr = match tracked.applied.get(&key) {
+                    Some(Update::Master { addr, .. }) => Some(addr),
+                    _ => None,
+                };
+                if previous_addr != Some(addr) {
+                    info!(
+                        master = target.master_name,
+                        service = key,
+                        new_master = format_addr(addr),
+                        "Checking the new master"
+                    );
+                    // checked aside, so the other services and the signals are not held up
+                    let (check, addr, checked_key) = (check.clone(), addr.clone(), key.clone());
+                    let handle = checks
+                        .spawn(async move { (checked_key, wait_for_master(&addr, &check).await) });
+                    tracked.checking.insert(key, (update, handle));
+                    continue;
+                }
+            }
+            tracked
+                .apply(&materializer, &kube_client, key, update)
+                .await;
+        }
+    }
 
-        println!("Received new master: {:?}", addr);
-        materialize_service(&addr);
+    info!("Shutting down");
+    drop(static_workers);
+    if let Some(controller) = &controller {
+        controller.abort();
+    }
+    if config.clear_on_shutdown && leading {
+        for (key, update) in &tracked.applied {
+            match clear_update(&materializer, update).await {
+                Ok(()) => info!(service = key, "Removed the endpoints"),
+                Err(err) => error!(
+                    service = key,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to remove the endpoints"
+                ),
+            }
+        }
+    }
+    if let Some((stop, elector)) = election {
+        let _ = stop.send(());
+        if let Err(err) = elector.await {
+            error!(error = %err, "The leader election failed to stop");
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_host_strips_brackets_and_canonicalizes() {
+        assert_eq!(normalize_host("[::1]"), "::1");
+        assert_eq!(normalize_host("::1"), "::1");
+        assert_eq!(normalize_host("0:0:0:0:0:0:0:1"), "::1");
+        assert_eq!(normalize_host("[FE80:0::0001]"), "fe80::1");
+        assert_eq!(normalize_host("10.0.0.1"), "10.0.0.1");
+        assert_eq!(normalize_host("redis-0.redis"), "redis-0.redis");
+        assert_eq!(normalize_host("[redis-0]"), "[redis-0]");
+    }
+
+    #[test]
+    fn format_addr_brackets_ipv6() {
+        assert_eq!(format_addr(&("::1".to_owned(), 26379)), "[::1]:26379");
+        assert_eq!(format_addr(&("fe80::1".to_owned(), 6379)), "[fe80::1]:6379");
+        assert_eq!(format_addr(&("10.0.0.1".to_owned(), 6379)), "10.0.0.1:6379");
+        assert_eq!(
+            format_addr(&("redis-0.redis".to_owned(), 6379)),
+            "redis-0.redis:6379"
+        );
     }
 }
@@ -0,0 +1,180 @@
+use std::{
+    sync::{atomic::AtomicU64, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+    registry::Registry,
+};
+
+use crate::{format_addr, leader, service::ServiceTarget, RedisAddr};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MasterLabels {
+    master: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MasterInfoLabels {
+    namespace: String,
+    service: String,
+    master: String,
+    address: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SentinelLabels {
+    sentinel: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KubernetesWriteLabels {
+    kind: String,
+    operation: String,
+    result: String,
+}
+
+struct Metrics {
+    registry: Registry,
+    failovers: Family<MasterLabels, Counter>,
+    last_switch: Family<MasterLabels, Gauge<f64, AtomicU64>>,
+    master_info: Family<MasterInfoLabels, Gauge>,
+    sentinel_errors: Family<SentinelLabels, Counter>,
+    get_master_duration: Family<SentinelLabels, Histogram>,
+    kubernetes_writes: Family<KubernetesWriteLabels, Counter>,
+    leader: Gauge,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let metrics = Metrics {
+        registry: Registry::with_prefix("redis_sentinel_service"),
+        failovers: Family::default(),
+        last_switch: Family::default(),
+        master_info: Family::default(),
+        sentinel_errors: Family::default(),
+        get_master_duration: Family::new_with_constructor(|| {
+            Histogram::new([0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0].into_iter())
+        }),
+        kubernetes_writes: Family::default(),
+        leader: Gauge::default(),
+    };
+    metrics.register()
+});
+
+impl Metrics {
+    fn register(mut self) -> Self {
+        self.registry.register(
+            "failovers",
+            "Master switches observed per master",
+            self.failovers.clone(),
+        );
+        self.registry.register(
+            "last_switch_timestamp_seconds",
+            "When the master last switched, in seconds since the epoch",
+            self.last_switch.clone(),
+        );
+        self.registry.register(
+            "master_info",
+            "The master each Service currently points to",
+            self.master_info.clone(),
+        );
+        self.registry.register(
+            "sentinel_errors",
+            "Failed connections and requests per sentinel",
+            self.sentinel_errors.clone(),
+        );
+        self.registry.register(
+            "get_master_duration_seconds",
+            "How long sentinels take to return the address of a master",
+            self.get_master_duration.clone(),
+        );
+        self.registry.register(
+            "kubernetes_writes",
+            "Writes to Kubernetes objects by kind, operation and result",
+            self.kubernetes_writes.clone(),
+        );
+        self.registry.register(
+            "leader",
+            "Whether this replica holds the lease and writes to Kubernetes",
+            self.leader.clone(),
+        );
+        self
+    }
+}
+
+fn master_info_labels(target: &ServiceTarget, addr: &RedisAddr) -> MasterInfoLabels {
+    MasterInfoLabels {
+        namespace: target.namespace.clone(),
+        service: target.name.clone(),
+        master: target.master_name.clone(),
+        address: format_addr(addr),
+    }
+}
+
+/// Records the master the Service of the target points to now.
+pub fn record_master(target: &ServiceTarget, addr: &RedisAddr) {
+    METRICS
+        .master_info
+        .get_or_create(&master_info_labels(target, addr))
+        .set(1);
+}
+
+/// Drops the info metric of a master the Service of the target no longer points to.
+pub fn forget_master(target: &ServiceTarget, addr: &RedisAddr) {
+    METRICS
+        .master_info
+        .remove(&master_info_labels(target, addr));
+}
+
+pub fn record_failover(master_name: &str) {
+    let labels = MasterLabels {
+        master: master_name.to_owned(),
+    };
+    METRICS.failovers.get_or_create(&labels).inc();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    METRICS
+        .last_switch
+        .get_or_create(&labels)
+        .set(now.as_secs_f64());
+}
+
+pub fn record_sentinel_error(sentinel: &str) {
+    METRICS
+        .sentinel_errors
+        .get_or_create(&SentinelLabels {
+            sentinel: sentinel.to_owned(),
+        })
+        .inc();
+}
+
+pub fn observe_get_master(sentinel: &str, duration: Duration) {
+    METRICS
+        .get_master_duration
+        .get_or_create(&SentinelLabels {
+            sentinel: sentinel.to_owned(),
+        })
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_kubernetes_write(kind: &str, operation: &str, success: bool) {
+    METRICS
+        .kubernetes_writes
+        .get_or_create(&KubernetesWriteLabels {
+            kind: kind.to_owned(),
+            operation: operation.to_owned(),
+            result: if success { "success" } else { "failure" }.to_owned(),
+        })
+        .inc();
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn encode_metrics() -> Result<String, std::fmt::Error> {
+    METRICS.leader.set(leader::is_leading().into());
+    let mut buffer = String::new();
+    encode(&mut buffer, &METRICS.registry)?;
+    Ok(buffer)
+}
@@ -0,0 +1,510 @@
+use std::{fmt::Display, net::IpAddr};
+
+use crate::{normalize_host, Error, RedisAddr};
+
+/// Longest host name DNS allows.
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Why the payload of an event could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    MissingFields { expected: usize, found: usize },
+    EmptyName,
+    InvalidHost(String),
+    InvalidPort(String),
+    InvalidEpoch(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MissingFields { expected, found } => {
+                write!(f, "expected {} fields, found {}", expected, found)
+            }
+            ParseError::EmptyName => write!(f, "master name is empty"),
+            ParseError::InvalidHost(host) => write!(f, "invalid host {:?}", host),
+            ParseError::InvalidPort(port) => write!(f, "invalid port {:?}", port),
+            ParseError::InvalidEpoch(epoch) => write!(f, "invalid epoch {:?}", epoch),
+        }
+    }
+}
+
+/// Accepts IP addresses, bracketed or not, and host names as announced with
+/// `resolve-hostnames yes`.
+fn parse_host(host: &str) -> Result<String, ParseError> {
+    let normalized = normalize_host(host);
+    if normalized.parse::<IpAddr>().is_ok() {
+        return Ok(normalized);
+    }
+    let valid = !normalized.is_empty()
+        && normalized.len() <= MAX_HOSTNAME_LEN
+        && !normalized.starts_with(['-', '.'])
+        && normalized
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'));
+    match valid {
+        true => Ok(normalized),
+        false => Err(ParseError::InvalidHost(host.to_owned())),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, ParseError> {
+    match port.parse() {
+        Ok(0) | Err(_) => Err(ParseError::InvalidPort(port.to_owned())),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn parse_addr(host: &str, port: &str) -> Result<RedisAddr, ParseError> {
+    Ok((parse_host(host)?, parse_port(port)?))
+}
+
+/// Splits the last whitespace separated field off the payload.
+fn split_last(payload: &str) -> (&str, &str) {
+    let payload = payload.trim_end_matches(|c: char| c.is_ascii_whitespace());
+    match payload.rfind(|c: char| c.is_ascii_whitespace()) {
+        Some(idx) => (&payload[..idx], &payload[idx + 1..]),
+        None => ("", payload),
+    }
+}
+
+/// The payload of `+switch-master`, `<master-name> <old-ip> <old-port> <new-ip> <new-port>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwitchMaster {
+    pub name: String,
+    pub old: RedisAddr,
+    pub new: RedisAddr,
+}
+
+impl SwitchMaster {
+    /// The addresses are taken from the end, so whatever precedes them is the master name, even
+    /// if it contains spaces.
+    pub fn parse(payload: &str) -> Result<Self, ParseError> {
+        let found = payload.split_ascii_whitespace().count();
+        if found < 5 {
+            return Err(ParseError::MissingFields { expected: 5, found });
+        }
+        let (rest, new_port) = split_last(payload);
+        let (rest, new_host) = split_last(rest);
+        let (rest, old_port) = split_last(rest);
+        let (name, old_host) = split_last(rest);
+        let name = name.trim_matches(|c: char| c.is_ascii_whitespace());
+        if name.is_empty() {
+            return Err(ParseError::EmptyName);
+        }
+        Ok(SwitchMaster {
+            name: name.to_owned(),
+            old: parse_addr(old_host, old_port)?,
+            new: parse_addr(new_host, new_port)?,
+        })
+    }
+}
+
+/// Writes the payload the way sentinels publish it.
+impl Display for SwitchMaster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.name, self.old.0, self.old.1, self.new.0, self.new.1
+        )
+    }
+}
+
+/// An instance as described by most events, like
+/// `<instance-type> <name> <ip> <port> @ <master-name> <master-ip> <master-port>`, where the
+/// `@ ...` part is omitted when the instance is a master itself. Some events append details like
+/// `#quorum 2/2`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instance {
+    /// One of master, slave or sentinel.
+    pub role: String,
+    pub name: String,
+    pub addr: RedisAddr,
+    /// The master the instance belongs to, unless it is a master itself.
+    pub master: Option<String>,
+}
+
+impl Instance {
+    pub fn parse(payload: &str) -> Result<Self, ParseError> {
+        let segments: Vec<&str> = payload.split_ascii_whitespace().collect();
+        let (role, name, host, port, master) = match segments[..] {
+            [role, name, host, port, "@", master, ..] => (role, name, host, port, Some(master)),
+            [role, name, host, port, ..] => (role, name, host, port, None),
+            _ => {
+                return Err(ParseError::MissingFields {
+                    expected: 4,
+                    found: segments.len(),
+                })
+            }
+        };
+        Ok(Instance {
+            role: role.to_owned(),
+            name: name.to_owned(),
+            addr: parse_addr(host, port)?,
+            master: master.map(str::to_owned),
+        })
+    }
+
+    /// The master the instance is or belongs to.
+    pub fn master_name(&self) -> &str {
+        self.master.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.role == "master"
+    }
+}
+
+/// An event published by a sentinel, named after the channel it was published on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SentinelEvent {
+    /// `+sdown` or `-sdown`, a single sentinel considers the instance down or no longer.
+    SubjectivelyDown { instance: Instance, down: bool },
+    /// `+odown` or `-odown`, a quorum of sentinels considers the master down or no longer.
+    ObjectivelyDown { instance: Instance, down: bool },
+    /// `+try-failover`, the sentinel is about to fail over the master.
+    TryFailover { instance: Instance },
+    /// `+failover-state-<state>`, the failover of the master moved on to the given state.
+    FailoverState { instance: Instance, state: String },
+    /// `+failover-end` or `+failover-end-for-timeout`.
+    FailoverEnd { instance: Instance },
+    /// `-failover-abort-<reason>`, the failover of the master was given up.
+    FailoverAbort { instance: Instance, reason: String },
+    /// `+new-epoch`, the current epoch was updated.
+    NewEpoch { epoch: u64 },
+    /// `+config-update-from`, the sentinel took over the configuration of the given sentinel.
+    ConfigUpdateFrom { instance: Instance },
+    /// `+tilt` or `-tilt`, the sentinel stopped or resumed acting, its answers may be stale
+    /// meanwhile.
+    Tilt { active: bool },
+    /// `+reset-master`, the master was reset by `SENTINEL RESET`.
+    ResetMaster { instance: Instance },
+    /// `+monitor`, the master was added by `SENTINEL MONITOR`, its config epoch starts over.
+    Monitor { instance: Instance },
+    /// `+switch-master`, the master changed its address.
+    SwitchMaster(SwitchMaster),
+    /// Any other event, like `+slave` or `+role-change`, with the instance it is about if any.
+    Other {
+        channel: String,
+        instance: Option<Instance>,
+    },
+}
+
+impl SentinelEvent {
+    pub fn parse(channel: &str, payload: &str) -> Result<Self, Error> {
+        match Self::parse_payload(channel, payload) {
+            Ok(event) => Ok(event),
+            Err(err) => Err(Error::InvalidResponse(format!(
+                "Invalid {} event {:?}: {}",
+                channel, payload, err
+            ))),
+        }
+    }
+
+    fn parse_payload(channel: &str, payload: &str) -> Result<Self, ParseError> {
+        let instance = || Instance::parse(payload);
+        let event = match channel {
+            "+switch-master" => SentinelEvent::SwitchMaster(SwitchMaster::parse(payload)?),
+            "+sdown" | "-sdown" => SentinelEvent::SubjectivelyDown {
+                instance: instance()?,
+                down: channel.starts_with('+'),
+            },
+            "+odown" | "-odown" => SentinelEvent::ObjectivelyDown {
+                instance: instance()?,
+                down: channel.starts_with('+'),
+            },
+            "+try-failover" => SentinelEvent::TryFailover {
+                instance: instance()?,
+            },
+            "+failover-end" | "+failover-end-for-timeout" => SentinelEvent::FailoverEnd {
+                instance: instance()?,
+            },
+            "+new-epoch" => match payload.trim().parse() {
+                Ok(epoch) => SentinelEvent::NewEpoch { epoch },
+                Err(_) => return Err(ParseError::InvalidEpoch(payload.to_owned())),
+            },
+            "+config-update-from" => SentinelEvent::ConfigUpdateFrom {
+                instance: instance()?,
+            },
+            "+tilt" | "-tilt" => SentinelEvent::Tilt {
+                active: channel.starts_with('+'),
+            },
+            "+reset-master" => SentinelEvent::ResetMaster {
+                instance: instance()?,
+            },
+            "+monitor" => SentinelEvent::Monitor {
+                instance: instance()?,
+            },
+            _ => {
+                if let Some(state) = channel.strip_prefix("+failover-state-") {
+                    SentinelEvent::FailoverState {
+                        instance: instance()?,
+                        state: state.to_owned(),
+                    }
+                } else if let Some(reason) = channel.strip_prefix("-failover-abort-") {
+                    SentinelEvent::FailoverAbort {
+                        instance: instance()?,
+                        reason: reason.to_owned(),
+                    }
+                } else {
+                    SentinelEvent::Other {
+                        channel: channel.to_owned(),
+                        instance: instance().ok(),
+                    }
+                }
+            }
+        };
+        Ok(event)
+    }
+
+    /// The master the event is about, if any.
+    pub fn master_name(&self) -> Option<&str> {
+        match self {
+            SentinelEvent::SubjectivelyDown { instance, .. }
+            | SentinelEvent::ObjectivelyDown { instance, .. }
+            | SentinelEvent::TryFailover { instance }
+            | SentinelEvent::FailoverState { instance, .. }
+            | SentinelEvent::FailoverEnd { instance }
+            | SentinelEvent::FailoverAbort { instance, .. }
+            | SentinelEvent::ConfigUpdateFrom { instance }
+            | SentinelEvent::ResetMaster { instance }
+            | SentinelEvent::Monitor { instance } => Some(instance.master_name()),
+            SentinelEvent::Other { instance, .. } => instance.as_ref().map(Instance::master_name),
+            SentinelEvent::SwitchMaster(switch) => Some(&switch.name),
+            SentinelEvent::NewEpoch { .. } | SentinelEvent::Tilt { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn addr(host: &str, port: u16) -> RedisAddr {
+        (host.to_owned(), port)
+    }
+
+    #[test]
+    fn switch_master_ipv4() {
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1 6379 10.0.0.2 6380"),
+            Ok(SwitchMaster {
+                name: "mymaster".to_owned(),
+                old: addr("10.0.0.1", 6379),
+                new: addr("10.0.0.2", 6380),
+            })
+        );
+    }
+
+    #[test]
+    fn switch_master_ipv6() {
+        assert_eq!(
+            SwitchMaster::parse("mymaster 2001:db8:0:0::1 6379 [2001:db8::2] 6379"),
+            Ok(SwitchMaster {
+                name: "mymaster".to_owned(),
+                old: addr("2001:db8::1", 6379),
+                new: addr("2001:db8::2", 6379),
+            })
+        );
+    }
+
+    #[test]
+    fn switch_master_hostnames() {
+        assert_eq!(
+            SwitchMaster::parse("mymaster redis-0.redis.default.svc 6379 redis-1.redis 6379"),
+            Ok(SwitchMaster {
+                name: "mymaster".to_owned(),
+                old: addr("redis-0.redis.default.svc", 6379),
+                new: addr("redis-1.redis", 6379),
+            })
+        );
+    }
+
+    #[test]
+    fn switch_master_odd_names() {
+        let switch = SwitchMaster::parse("  my master:{1}  ::1 6379\t::2 6379 \r\n").unwrap();
+        assert_eq!(switch.name, "my master:{1}");
+        assert_eq!(switch.old, addr("::1", 6379));
+        assert_eq!(switch.new, addr("::2", 6379));
+    }
+
+    #[test]
+    fn switch_master_malformed() {
+        assert_eq!(
+            SwitchMaster::parse(""),
+            Err(ParseError::MissingFields {
+                expected: 5,
+                found: 0
+            })
+        );
+        assert_eq!(
+            SwitchMaster::parse("10.0.0.1 6379 10.0.0.2 6379"),
+            Err(ParseError::MissingFields {
+                expected: 5,
+                found: 4
+            })
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1 6379 10.0.0.2 65536"),
+            Err(ParseError::InvalidPort("65536".to_owned()))
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1 0 10.0.0.2 6379"),
+            Err(ParseError::InvalidPort("0".to_owned()))
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1 6379 10.0.0.2 -1"),
+            Err(ParseError::InvalidPort("-1".to_owned()))
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1:6379 6379 10.0.0.2 6379"),
+            Err(ParseError::InvalidHost("10.0.0.1:6379".to_owned()))
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster [redis] 6379 10.0.0.2 6379"),
+            Err(ParseError::InvalidHost("[redis]".to_owned()))
+        );
+        assert_eq!(
+            SwitchMaster::parse("mymaster 10.0.0.1 6379 -redis 6379"),
+            Err(ParseError::InvalidHost("-redis".to_owned()))
+        );
+    }
+
+    #[test]
+    fn instance_of_replica() {
+        let instance =
+            Instance::parse("slave 10.0.0.2:6379 10.0.0.2 6379 @ mymaster 10.0.0.1 6379").unwrap();
+        assert_eq!(instance.role, "slave");
+        assert_eq!(instance.addr, addr("10.0.0.2", 6379));
+        assert_eq!(instance.master_name(), "mymaster");
+        assert!(!instance.is_master());
+    }
+
+    #[test]
+    fn objectively_down_with_quorum() {
+        let event = SentinelEvent::parse("+odown", "master mymaster ::1 6379 #quorum 2/2").unwrap();
+        assert_eq!(
+            event,
+            SentinelEvent::ObjectivelyDown {
+                instance: Instance {
+                    role: "master".to_owned(),
+                    name: "mymaster".to_owned(),
+                    addr: addr("::1", 6379),
+                    master: None,
+                },
+                down: true,
+            }
+        );
+        assert_eq!(event.master_name(), Some("mymaster"));
+    }
+
+    #[test]
+    fn failover_events() {
+        let payload = "master mymaster 10.0.0.1 6379";
+        assert!(matches!(
+            SentinelEvent::parse("+failover-state-select-slave", payload),
+            Ok(SentinelEvent::FailoverState { state, .. }) if state == "select-slave"
+        ));
+        assert!(matches!(
+            SentinelEvent::parse("-failover-abort-no-good-slave", payload),
+            Ok(SentinelEvent::FailoverAbort { reason, .. }) if reason == "no-good-slave"
+        ));
+        assert_eq!(
+            SentinelEvent::parse("+new-epoch", "42").unwrap(),
+            SentinelEvent::NewEpoch { epoch: 42 }
+        );
+        assert!(SentinelEvent::parse("+new-epoch", "forty-two").is_err());
+        assert!(SentinelEvent::parse("+try-failover", "master mymaster").is_err());
+    }
+
+    #[test]
+    fn other_events() {
+        assert_eq!(
+            SentinelEvent::parse("+tilt", "#tilt mode entered").unwrap(),
+            SentinelEvent::Tilt { active: true }
+        );
+        assert_eq!(
+            SentinelEvent::parse("+monitor", "master mymaster 10.0.0.1 6379 quorum 2").unwrap(),
+            SentinelEvent::Monitor {
+                instance: Instance {
+                    role: "master".to_owned(),
+                    name: "mymaster".to_owned(),
+                    addr: addr("10.0.0.1", 6379),
+                    master: None,
+                },
+            }
+        );
+        assert_eq!(
+            SentinelEvent::parse("+slave-reconf-sent", "bogus").unwrap(),
+            SentinelEvent::Other {
+                channel: "+slave-reconf-sent".to_owned(),
+                instance: None,
+            }
+        );
+    }
+
+    fn host() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<Ipv4Addr>().prop_map(|ip| ip.to_string()),
+            any::<Ipv6Addr>().prop_map(|ip| ip.to_string()),
+            "[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?(\\.[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?){0,3}",
+        ]
+    }
+
+    fn switch_master() -> impl Strategy<Value = SwitchMaster> {
+        (
+            "[!-~]([!-~ ]{0,20}[!-~])?",
+            host(),
+            1..=u16::MAX,
+            host(),
+            1..=u16::MAX,
+        )
+            .prop_map(
+                |(name, old_host, old_port, new_host, new_port)| SwitchMaster {
+                    name,
+                    old: (old_host, old_port),
+                    new: (new_host, new_port),
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn switch_master_round_trips(switch in switch_master()) {
+            prop_assert_eq!(SwitchMaster::parse(&switch.to_string()), Ok(switch));
+        }
+
+        #[test]
+        fn switch_master_accepts_bracketed_ipv6(
+            old in any::<Ipv6Addr>(),
+            new in any::<Ipv6Addr>(),
+            port in 1..=u16::MAX,
+        ) {
+            let switch = SwitchMaster::parse(&format!("m [{}] {} [{}] {}", old, port, new, port));
+            prop_assert_eq!(
+                switch,
+                Ok(SwitchMaster {
+                    name: "m".to_owned(),
+                    old: (old.to_string(), port),
+                    new: (new.to_string(), port),
+                })
+            );
+        }
+
+        #[test]
+        fn switch_master_rejects_invalid_ports(switch in switch_master(), port in "[1-9][0-9]{5,}|-[0-9]+|[a-z]+") {
+            let payload = format!("{} {}", switch.to_string().rsplit_once(' ').unwrap().0, port);
+            prop_assert_eq!(SwitchMaster::parse(&payload), Err(ParseError::InvalidPort(port)));
+        }
+
+        #[test]
+        fn parsing_never_panics(channel in "[+-][a-z-]{1,30}", payload in "\\PC*") {
+            let _ = SentinelEvent::parse(&channel, &payload);
+        }
+    }
+}
@@ -0,0 +1,1373 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use futures::{future::join_all, StreamExt};
+use redis::{
+    aio::{MultiplexedConnection, PubSub},
+    cmd, from_redis_value, pipe, AsyncConnectionConfig, Client, Cmd, ConnectionAddr,
+    ConnectionInfo, RedisConnectionInfo, TlsCertificates, Value,
+};
+use tokio::{
+    sync::{mpsc::UnboundedSender as Sender, oneshot, watch},
+    task::{self, JoinHandle, JoinSet},
+    time::{interval, sleep, timeout},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backoff::Backoff, events, format_addr, health::WorkerHealth, metrics, normalize_host,
+    parser::SentinelEvent, service::ServiceTarget, Error, RedisAddr,
+};
+
+const SWITCH_QUORUM_ATTEMPTS: u32 = 5;
+const SWITCH_QUORUM_RETRY_DELAY: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+const RESTART_WINDOW: Duration = Duration::from_secs(300);
+const MAX_RESTARTS: usize = 5;
+/// Sentinels publish every event on a channel named after it.
+const ALL_EVENTS_PATTERN: &str = "*";
+
+/// The services to materialize, by the name of the master they follow.
+pub type Targets = HashMap<String, Arc<ServiceTarget>>;
+
+#[derive(PartialEq)]
+pub enum Update {
+    Master {
+        target: Arc<ServiceTarget>,
+        addr: RedisAddr,
+        /// The config epoch the master was elected in.
+        epoch: u64,
+    },
+    Replicas {
+        target: Arc<ServiceTarget>,
+        addrs: Vec<RedisAddr>,
+    },
+    /// No master is safe to write to, the Service of the target is left without endpoints.
+    Fenced { target: Arc<ServiceTarget> },
+    /// A sentinel published an event about the master of the target, or about itself. Events are
+    /// informational, the outcome is sent as one of the other updates.
+    Event {
+        target: Arc<ServiceTarget>,
+        sentinel: String,
+        event: SentinelEvent,
+    },
+    /// The workers of the target were replaced or stopped, its master is to be forgotten.
+    Forget { target: Arc<ServiceTarget> },
+    /// The target no longer uses the given Service after its spec changed, it is to be deleted.
+    Retire {
+        target: Arc<ServiceTarget>,
+        service_name: String,
+    },
+}
+
+/// What to do with the Service of a master the sentinels cannot agree on.
+#[derive(Clone, Copy, Debug)]
+pub enum Fencing {
+    /// Keep pointing to the last known master.
+    Keep,
+    /// Remove the endpoints as soon as the master is objectively down, or once no quorum agreed
+    /// on a master for the given time, so clients fail fast instead of writing to a demoted node.
+    Clear { after: Duration },
+}
+
+impl Update {
+    /// Identifies the service an update is for, a later update with the same key supersedes
+    /// earlier ones.
+    pub fn key(&self) -> String {
+        match self {
+            Update::Master { target, .. }
+            | Update::Fenced { target }
+            | Update::Event { target, .. }
+            | Update::Forget { target } => target.key(),
+            Update::Replicas { target, .. } => format!(
+                "{}/{}",
+                target.namespace,
+                target.replica_name.as_deref().unwrap_or_default()
+            ),
+            Update::Retire {
+                target,
+                service_name,
+            } => format!("{}/{}", target.namespace, service_name),
+        }
+    }
+}
+
+/// What the workers last concluded about the master of a target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MasterState {
+    Master {
+        addr: RedisAddr,
+        epoch: u64,
+    },
+    /// No master is safe to write to.
+    Fenced,
+}
+
+impl MasterState {
+    /// Whether this state, from a query started at the given time, may replace the earlier one
+    /// concluded at the other time. The poller and the listeners race, so a reply from before a
+    /// failover may arrive last. A master from an older config epoch is only dropped when its
+    /// query started before the newer one was concluded, the sentinels may have started over
+    /// without anyone noticing.
+    fn supersedes(
+        &self,
+        queried_at: Instant,
+        earlier: &MasterState,
+        concluded_at: Instant,
+    ) -> bool {
+        match (self, earlier) {
+            (MasterState::Master { epoch, .. }, MasterState::Master { epoch: earlier, .. }) => {
+                epoch >= earlier || queried_at > concluded_at
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A group of tasks following a set of masters, they are cancelled when this is dropped.
+pub struct Workers {
+    supervisor: JoinHandle<()>,
+    /// Asks the supervisor to stop its workers and wait for them.
+    stop: Option<oneshot::Sender<()>>,
+}
+
+/// How to connect and authenticate to the sentinels.
+#[derive(Clone, Default)]
+pub struct ConnectionOptions {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    /// Custom CA and client certificates, otherwise the system trust store is used.
+    pub certificates: Option<TlsCertificates>,
+}
+
+/// How a new master is checked before the Services are switched to it.
+#[derive(Clone)]
+pub struct MasterCheck {
+    /// How long to keep checking before the new master is given up on.
+    pub timeout: Duration,
+    /// Also wait for the master to finish loading its dataset.
+    pub wait_for_loading: bool,
+    /// The masters are connected to like the sentinels.
+    pub connection: ConnectionOptions,
+}
+
+pub struct Sentinel {
+    pub addr: String,
+    client: Client,
+}
+
+pub struct SentinelSet {
+    sentinels: Vec<Sentinel>,
+    quorum: usize,
+}
+
+fn get_master_from_sentinel_cmd(name: &str) -> Cmd {
+    let mut cmd = cmd("SENTINEL");
+    cmd.arg("get-master-addr-by-name").arg(name);
+    cmd
+}
+
+fn get_master_state_cmd(name: &str) -> Cmd {
+    let mut cmd = cmd("SENTINEL");
+    cmd.arg("master").arg(name);
+    cmd
+}
+
+/// Returns the address of the master, whether the sentinel considers it objectively down and the
+/// config epoch it was elected in.
+async fn get_master_from_sentinel(
+    connection: &mut MultiplexedConnection,
+    master_name: &str,
+) -> Result<(RedisAddr, bool, u64), Error> {
+    let (response, state) = match pipe()
+        .add_command(get_master_from_sentinel_cmd(master_name))
+        .add_command(get_master_state_cmd(master_name))
+        .query_async::<(Option<Vec<String>>, HashMap<String, String>)>(connection)
+        .await
+    {
+        Ok(response) => response,
+        Err(redis_err) => return Err(Error::RedisErr(redis_err)),
+    };
+    let Some(response) = response else {
+        return Err(Error::InvalidResponse(format!(
+            "Sentinel does not know a master named {}",
+            master_name
+        )));
+    };
+
+    if response.len() != 2 {
+        return Err(Error::InvalidResponse(
+            "Response did not have exactly 2 elements!".to_owned(),
+        ));
+    }
+
+    let host = normalize_host(&response[0]);
+    let port: u16 = match response[1].parse() {
+        Ok(p) => p,
+        Err(err) => return Err(Error::InvalidResponse(format!("Port is invalid: {}", err))),
+    };
+    let down = state
+        .get("flags")
+        .is_some_and(|flags| flags.split(',').any(|flag| flag == "o_down"));
+    let epoch: u64 = match state.get("config-epoch").map(|epoch| epoch.parse()) {
+        Some(Ok(epoch)) => epoch,
+        Some(Err(err)) => {
+            return Err(Error::InvalidResponse(format!(
+                "Config epoch is invalid: {}",
+                err
+            )))
+        }
+        None => {
+            return Err(Error::InvalidResponse(format!(
+                "Sentinel did not return the config epoch of {}",
+                master_name
+            )))
+        }
+    };
+
+    Ok(((host, port), down, epoch))
+}
+
+fn get_replicas_from_sentinel_cmd(name: &str) -> Cmd {
+    let mut cmd = cmd("SENTINEL");
+    cmd.arg("replicas").arg(name);
+    cmd
+}
+
+/// A replica is healthy if no sentinel considers it down and it is connected to its master.
+fn is_healthy_replica(replica: &HashMap<String, String>) -> bool {
+    let down = replica.get("flags").is_some_and(|flags| {
+        flags
+            .split(',')
+            .any(|flag| matches!(flag, "s_down" | "o_down" | "disconnected"))
+    });
+    let link_ok = replica
+        .get("master-link-status")
+        .is_some_and(|status| status == "ok");
+    !down && link_ok
+}
+
+async fn get_replicas_from_sentinel(
+    connection: &mut MultiplexedConnection,
+    master_name: &str,
+) -> Result<Vec<RedisAddr>, Error> {
+    let response = match get_replicas_from_sentinel_cmd(master_name)
+        .query_async::<Vec<HashMap<String, String>>>(connection)
+        .await
+    {
+        Ok(response) => response,
+        Err(redis_err) => return Err(Error::RedisErr(redis_err)),
+    };
+
+    let mut replicas = Vec::new();
+    for replica in response
+        .iter()
+        .filter(|replica| is_healthy_replica(replica))
+    {
+        let (Some(host), Some(port)) = (replica.get("ip"), replica.get("port")) else {
+            return Err(Error::InvalidResponse(
+                "Replica without ip or port!".to_owned(),
+            ));
+        };
+        let port: u16 = match port.parse() {
+            Ok(p) => p,
+            Err(err) => return Err(Error::InvalidResponse(format!("Port is invalid: {}", err))),
+        };
+        replicas.push((normalize_host(host), port));
+    }
+    // sentinels do not guarantee any order, but updates are compared to the previous ones
+    replicas.sort();
+
+    Ok(replicas)
+}
+
+/// Fails unless the instance is a master and, if wanted, finished loading its dataset.
+async fn check_master_role(
+    connection: &mut MultiplexedConnection,
+    wait_for_loading: bool,
+) -> Result<(), Error> {
+    let response = match cmd("ROLE").query_async::<Vec<Value>>(connection).await {
+        Ok(response) => response,
+        Err(redis_err) => return Err(Error::RedisErr(redis_err)),
+    };
+    let role: String = match response.first().map(from_redis_value) {
+        Some(Ok(role)) => role,
+        _ => {
+            return Err(Error::InvalidResponse(
+                "ROLE response without a role!".to_owned(),
+            ))
+        }
+    };
+    if role != "master" {
+        return Err(Error::InvalidResponse(format!(
+            "Instance is a {}, not a master",
+            role
+        )));
+    }
+    if !wait_for_loading {
+        return Ok(());
+    }
+
+    let info = match cmd("INFO")
+        .arg("persistence")
+        .query_async::<String>(connection)
+        .await
+    {
+        Ok(info) => info,
+        Err(redis_err) => return Err(Error::RedisErr(redis_err)),
+    };
+    if info.lines().any(|line| line.trim() == "loading:1") {
+        return Err(Error::InvalidResponse(
+            "Master is still loading its dataset".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Connects to the announced master until it reports to be one, sentinels may announce a master
+/// before it accepts writes. Gives up with the last error once the timeout of the check passed.
+pub async fn wait_for_master(addr: &RedisAddr, check: &MasterCheck) -> Result<(), Error> {
+    let client = open_client(addr.clone(), &check.connection)?;
+    let deadline = Instant::now() + check.timeout;
+    let mut backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX);
+    loop {
+        let result = match connect_client(&client).await {
+            Ok(mut connection) => check_master_role(&mut connection, check.wait_for_loading).await,
+            Err(err) => Err(err),
+        };
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let delay = backoff.next_delay();
+        if Instant::now() + delay >= deadline {
+            return Err(err);
+        }
+        debug!(
+            address = format_addr(addr),
+            error.kind = err.kind(),
+            error = %err,
+            "New master is not ready yet"
+        );
+        sleep(delay).await;
+    }
+}
+
+/// Parses a `host:port` address, the host may be an IPv6 address in brackets.
+pub fn parse_host_port(addr: &str) -> Result<RedisAddr, Error> {
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return Err(Error::InvalidConfig(format!(
+            "Address {} is missing a port",
+            addr
+        )));
+    };
+    let port: u16 = match port.parse() {
+        Ok(p) => p,
+        Err(err) => {
+            return Err(Error::InvalidConfig(format!(
+                "Port of {} is invalid: {}",
+                addr, err
+            )))
+        }
+    };
+    Ok((normalize_host(host), port))
+}
+
+fn open_client((host, port): RedisAddr, options: &ConnectionOptions) -> Result<Client, Error> {
+    let connection_info = ConnectionInfo {
+        addr: if options.tls {
+            ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: false,
+                tls_params: None,
+            }
+        } else {
+            ConnectionAddr::Tcp(host, port)
+        },
+        redis: RedisConnectionInfo {
+            username: options.username.clone(),
+            password: options.password.clone(),
+            ..RedisConnectionInfo::default()
+        },
+    };
+    let client = match &options.certificates {
+        Some(certificates) if options.tls => {
+            Client::build_with_tls(connection_info, certificates.clone())
+        }
+        _ => Client::open(connection_info),
+    };
+    match client {
+        Ok(client) => Ok(client),
+        Err(err) => Err(Error::RedisErr(err)),
+    }
+}
+
+fn timed_out() -> Error {
+    Error::RedisErr(io::Error::from(io::ErrorKind::TimedOut).into())
+}
+
+/// Connects with timeouts, so an unreachable instance does not block a worker forever.
+async fn connect_client(client: &Client) -> Result<MultiplexedConnection, Error> {
+    let config = AsyncConnectionConfig::new()
+        .set_connection_timeout(CONNECT_TIMEOUT)
+        .set_response_timeout(READ_TIMEOUT);
+    match client
+        .get_multiplexed_async_connection_with_config(&config)
+        .await
+    {
+        Ok(connection) => Ok(connection),
+        Err(err) => Err(Error::RedisErr(err)),
+    }
+}
+
+impl Sentinel {
+    pub fn open(addr: &str, options: &ConnectionOptions) -> Result<Self, Error> {
+        let client = open_client(parse_host_port(addr)?, options)?;
+        Ok(Sentinel {
+            addr: addr.to_owned(),
+            client,
+        })
+    }
+
+    async fn connect(&self) -> Result<MultiplexedConnection, Error> {
+        let result = connect_client(&self.client).await;
+        if result.is_err() {
+            metrics::record_sentinel_error(&self.addr);
+        }
+        result
+    }
+
+    /// Subscribes to the channels matching the pattern, with the same timeouts as other
+    /// connections.
+    async fn psubscribe(&self, pattern: &str) -> Result<PubSub, Error> {
+        let result = match timeout(CONNECT_TIMEOUT, self.client.get_async_pubsub()).await {
+            Ok(Ok(mut pubsub)) => match timeout(READ_TIMEOUT, pubsub.psubscribe(pattern)).await {
+                Ok(Ok(())) => Ok(pubsub),
+                Ok(Err(err)) => Err(Error::RedisErr(err)),
+                Err(_) => Err(timed_out()),
+            },
+            Ok(Err(err)) => Err(Error::RedisErr(err)),
+            Err(_) => Err(timed_out()),
+        };
+        if result.is_err() {
+            metrics::record_sentinel_error(&self.addr);
+        }
+        result
+    }
+
+    async fn get_master(&self, master_name: &str) -> Result<(RedisAddr, bool, u64), Error> {
+        let mut connection = self.connect().await?;
+        let start = Instant::now();
+        let result = get_master_from_sentinel(&mut connection, master_name).await;
+        metrics::observe_get_master(&self.addr, start.elapsed());
+        if result.is_err() {
+            metrics::record_sentinel_error(&self.addr);
+        }
+        result
+    }
+
+    async fn get_replicas(&self, master_name: &str) -> Result<Vec<RedisAddr>, Error> {
+        let mut connection = self.connect().await?;
+        let result = get_replicas_from_sentinel(&mut connection, master_name).await;
+        if result.is_err() {
+            metrics::record_sentinel_error(&self.addr);
+        }
+        result
+    }
+}
+
+/// Decides on the master from the answers of the sentinels, each being the address of the master,
+/// whether it is objectively down and its config epoch. The address most sentinels agree on wins,
+/// a tie goes to the newer config epoch and an exact tie is no agreement at all. Fewer answers
+/// than the quorum tell nothing, unless the master is reported down.
+fn elect_master(
+    master_name: &str,
+    answers: Vec<(RedisAddr, bool, u64)>,
+    sentinels: usize,
+    quorum: usize,
+) -> Result<(RedisAddr, u64), Error> {
+    let answered = answers.len();
+    // the number of sentinels and the highest config epoch by address
+    let mut votes: HashMap<RedisAddr, (usize, u64)> = HashMap::new();
+    let mut down: Vec<RedisAddr> = Vec::new();
+    for (addr, is_down, epoch) in answers {
+        if is_down {
+            down.push(addr.clone());
+        }
+        let (count, max_epoch) = votes.entry(addr).or_default();
+        *count += 1;
+        *max_epoch = epoch.max(*max_epoch);
+    }
+    let mut ranked: Vec<(RedisAddr, (usize, u64))> = votes.into_iter().collect();
+    // the most votes first, then the newest config epoch
+    ranked.sort_by(|(_, tally), (_, other)| other.cmp(tally));
+
+    let reported_down = ranked.first().is_some_and(|(addr, _)| down.contains(addr));
+
+    match ranked.as_slice() {
+        _ if answered < quorum && !reported_down => Err(Error::Unreachable(format!(
+            "Only {} of {} sentinels answered for {}, {} required",
+            answered, sentinels, master_name, quorum
+        ))),
+        [(addr, tally), (other, other_tally), ..] if tally == other_tally => {
+            Err(Error::NoQuorum(format!(
+                "Sentinels are split between {} and {} as master of {}",
+                format_addr(addr),
+                format_addr(other),
+                master_name
+            )))
+        }
+        // a sentinel only flags a master objectively down after a quorum of them agreed on that
+        [(addr, _), ..] if reported_down => Err(Error::MasterDown(format!(
+            "Sentinels consider master {} of {} objectively down",
+            format_addr(addr),
+            master_name
+        ))),
+        [(addr, (count, epoch)), ..] if *count >= quorum => Ok((addr.clone(), *epoch)),
+        [(addr, (count, _)), ..] => Err(Error::NoQuorum(format!(
+            "Only {} of {} sentinels agree on {} as master of {}, {} required",
+            count,
+            sentinels,
+            format_addr(addr),
+            master_name,
+            quorum
+        ))),
+        [] => Err(Error::NoQuorum(format!(
+            "No sentinel returned a master for {}",
+            master_name
+        ))),
+    }
+}
+
+impl SentinelSet {
+    /// Creates a set of sentinels, a quorum of `None` requires a majority of them to agree.
+    pub fn new(sentinels: Vec<Sentinel>, quorum: Option<usize>) -> Result<Self, Error> {
+        let quorum = quorum.unwrap_or(sentinels.len() / 2 + 1);
+        if quorum == 0 || quorum > sentinels.len() {
+            return Err(Error::InvalidConfig(format!(
+                "Quorum must be between 1 and {}, got {}",
+                sentinels.len(),
+                quorum
+            )));
+        }
+        Ok(SentinelSet { sentinels, quorum })
+    }
+
+    /// Asks all sentinels concurrently for the current master and returns the address at least
+    /// `quorum` of them agree on, along with the config epoch it was elected in.
+    pub async fn query_master(&self, master_name: &str) -> Result<(RedisAddr, u64), Error> {
+        self.query_master_reporting(master_name, |_, _| {}).await
+    }
+
+    /// Like [`SentinelSet::query_master`], additionally reporting every sentinel that failed to
+    /// answer.
+    async fn query_master_reporting(
+        &self,
+        master_name: &str,
+        mut report: impl FnMut(&Sentinel, &Error),
+    ) -> Result<(RedisAddr, u64), Error> {
+        let answers = join_all(
+            self.sentinels
+                .iter()
+                .map(|sentinel| sentinel.get_master(master_name)),
+        )
+        .await;
+
+        let mut answered = Vec::new();
+        for (sentinel, answer) in self.sentinels.iter().zip(answers) {
+            match answer {
+                Ok(answer) => answered.push(answer),
+                Err(err) => {
+                    warn!(
+                        master = master_name,
+                        sentinel = sentinel.addr,
+                        error.kind = err.kind(),
+                        error = %err,
+                        "Failed to get the master from a sentinel"
+                    );
+                    report(sentinel, &err);
+                }
+            }
+        }
+        elect_master(master_name, answered, self.sentinels.len(), self.quorum)
+    }
+
+    /// Returns the healthy replicas as seen by the first sentinel that answers. Unlike the master,
+    /// the replicas do not need a quorum, a stale answer at worst routes reads to a lagging node.
+    pub async fn query_replicas(&self, master_name: &str) -> Result<Vec<RedisAddr>, Error> {
+        let mut last_err = None;
+        for sentinel in &self.sentinels {
+            match sentinel.get_replicas(master_name).await {
+                Ok(replicas) => return Ok(replicas),
+                Err(err) => {
+                    warn!(
+                        master = master_name,
+                        sentinel = sentinel.addr,
+                        error.kind = err.kind(),
+                        error = %err,
+                        "Failed to get the replicas from a sentinel"
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::InvalidConfig("No sentinels configured".to_owned())))
+    }
+
+    /// Sentinels do not all learn about a switch at the same moment, so the quorum query is
+    /// retried a couple of times before giving up and leaving it to the poller.
+    async fn query_master_after_switch(
+        &self,
+        master_name: &str,
+    ) -> Result<(RedisAddr, u64), Error> {
+        let mut attempt = 1;
+        loop {
+            match self.query_master(master_name).await {
+                Ok(master) => return Ok(master),
+                Err(err) if attempt >= SWITCH_QUORUM_ATTEMPTS => return Err(err),
+                Err(_) => {
+                    attempt += 1;
+                    sleep(SWITCH_QUORUM_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+/// The state the workers last concluded on for the master of a target and when, if any.
+type Conclusion = Option<(MasterState, Instant)>;
+
+/// Everything the worker tasks of one [`Workers`] group share.
+struct WorkerContext {
+    sentinels: Arc<SentinelSet>,
+    sender: Sender<Update>,
+    targets: Targets,
+    poll_interval: Duration,
+    fencing: Fencing,
+    /// Since when no master was agreed on, by master name.
+    no_master_since: Mutex<HashMap<String, Instant>>,
+    /// The current master of each target by master name and when it was concluded on, whoever
+    /// concludes last wins unless the conclusion is stale, see [`MasterState::supersedes`].
+    masters: HashMap<String, watch::Sender<Conclusion>>,
+    /// The last master each target was concluded on by master name, kept while fenced so a
+    /// failover is counted on every replica, leading or not.
+    last_masters: Mutex<HashMap<String, RedisAddr>>,
+    /// What the forwarders pass on to the main loop.
+    forwarded: Vec<(Arc<ServiceTarget>, watch::Receiver<Conclusion>)>,
+    health: Arc<WorkerHealth>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Role {
+    /// Subscribes to the events of the sentinel with the given index.
+    Listener(usize),
+    Poller,
+    /// Passes the master of the forwarded target with the given index on to the main loop.
+    Forwarder(usize),
+}
+
+fn send_update(sender: &Sender<Update>, update: Update) -> Result<(), Error> {
+    match sender.send(update) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(Error::ChannelClosed),
+    }
+}
+
+/// Concludes on the master of the given master name from a query started at the given time.
+/// Every conclusion is passed on, even an unchanged one, so the main loop retries updates that
+/// failed to apply. Only a stale one is dropped.
+fn publish_master(ctx: &WorkerContext, master_name: &str, state: MasterState, queried_at: Instant) {
+    if let Some(master) = ctx.masters.get(master_name) {
+        let published = master.send_if_modified(|current| match current {
+            Some((current, concluded_at))
+                if !state.supersedes(queried_at, current, *concluded_at) =>
+            {
+                debug!(
+                    master = master_name,
+                    ?current,
+                    stale = ?state,
+                    "Dropping a master from an older config epoch"
+                );
+                false
+            }
+            _ => {
+                *current = Some((state.clone(), Instant::now()));
+                true
+            }
+        });
+        if let (true, MasterState::Master { addr, .. }) = (published, state) {
+            let previous = ctx
+                .last_masters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(master_name.to_owned(), addr.clone());
+            if previous.is_some_and(|previous| previous != addr) {
+                metrics::record_failover(master_name);
+            }
+        }
+    }
+}
+
+/// Publishes the master of the target the sentinels agree on. Otherwise the Service is fenced if
+/// the policy says so, and the error is returned either way.
+async fn refresh_master(
+    ctx: &WorkerContext,
+    master_name: &str,
+    target: &Arc<ServiceTarget>,
+) -> Result<(), Error> {
+    let queried_at = Instant::now();
+    let result = ctx
+        .sentinels
+        .query_master_reporting(master_name, |sentinel, err| {
+            events::sentinel_failed(target, &sentinel.addr, err)
+        })
+        .await;
+    let mut no_master_since = ctx
+        .no_master_since
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let err = match result {
+        Ok((addr, epoch)) => {
+            ctx.health.sentinel_reached();
+            no_master_since.remove(master_name);
+            let state = MasterState::Master { addr, epoch };
+            publish_master(ctx, master_name, state, queried_at);
+            return Ok(());
+        }
+        // too few answers tell nothing about the master, the endpoints are kept as they are
+        Err(err @ Error::Unreachable(_)) => return Err(err),
+        Err(err) => err,
+    };
+    if matches!(err, Error::MasterDown(_)) {
+        ctx.health.sentinel_reached();
+    }
+
+    let since = *no_master_since
+        .entry(master_name.to_owned())
+        .or_insert_with(Instant::now);
+    let fence = match ctx.fencing {
+        Fencing::Keep => false,
+        Fencing::Clear { after } => matches!(err, Error::MasterDown(_)) || since.elapsed() >= after,
+    };
+    if fence {
+        warn!(
+            master = master_name,
+            service = target.key(),
+            error.kind = err.kind(),
+            error = %err,
+            "No master to point to, fencing the service"
+        );
+        publish_master(ctx, master_name, MasterState::Fenced, queried_at);
+    }
+    Err(err)
+}
+
+async fn refresh_replicas(
+    ctx: &WorkerContext,
+    master_name: &str,
+    target: &Arc<ServiceTarget>,
+) -> Result<(), Error> {
+    if target.replica_name.is_none() {
+        return Ok(());
+    }
+    let addrs = ctx.sentinels.query_replicas(master_name).await?;
+    send_update(
+        &ctx.sender,
+        Update::Replicas {
+            target: target.clone(),
+            addrs,
+        },
+    )
+}
+
+/// Refreshes the replicas after an event that may have changed them.
+async fn refresh_replicas_after_event(
+    ctx: &WorkerContext,
+    master_name: &str,
+    target: &Arc<ServiceTarget>,
+) -> Result<(), Error> {
+    match refresh_replicas(ctx, master_name, target).await {
+        Err(Error::ChannelClosed) => Err(Error::ChannelClosed),
+        Err(err) => {
+            warn!(
+                master = master_name,
+                error.kind = err.kind(),
+                error = %err,
+                "Failed to get replicas"
+            );
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Handles a single event, only failing when the updates cannot be delivered anymore. Events
+/// about the followed masters are passed on to the main loop, some of them additionally make
+/// the workers ask the sentinels for the outcome right away.
+async fn handle_event(
+    ctx: &WorkerContext,
+    sentinel: &Sentinel,
+    channel: &str,
+    payload: &str,
+) -> Result<(), Error> {
+    let event = match SentinelEvent::parse(channel, payload) {
+        Ok(event) => event,
+        Err(err) => {
+            warn!(
+                sentinel = sentinel.addr,
+                channel,
+                payload,
+                error.kind = err.kind(),
+                error = %err,
+                "Received invalid event"
+            );
+            return Ok(());
+        }
+    };
+    debug!(sentinel = sentinel.addr, channel, payload, "Received event");
+
+    let target = match event.master_name() {
+        Some(master_name) => match ctx.targets.get(master_name) {
+            Some(target) => Some(target),
+            None => {
+                debug!(
+                    master = master_name,
+                    channel, "Event about a master we are not interested in..."
+                );
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    // the config epoch starts over, so the master concluded on before must not outrank the next
+    if let SentinelEvent::ResetMaster { instance } | SentinelEvent::Monitor { instance } = &event {
+        if let Some(master) = ctx.masters.get(instance.master_name()) {
+            master.send_replace(None);
+        }
+        ctx.last_masters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(instance.master_name());
+    }
+    // events about the sentinel itself concern all the masters it monitors
+    let concerned = match target {
+        Some(target) => vec![target],
+        None => ctx.targets.values().collect(),
+    };
+    for target in concerned {
+        send_update(
+            &ctx.sender,
+            Update::Event {
+                target: target.clone(),
+                sentinel: sentinel.addr.clone(),
+                event: event.clone(),
+            },
+        )?;
+    }
+    let Some(target) = target else {
+        return Ok(());
+    };
+
+    match &event {
+        SentinelEvent::SwitchMaster(switch) => {
+            let master = &switch.name;
+            info!(
+                master,
+                sentinel = sentinel.addr,
+                old_master = format_addr(&switch.old),
+                new_master = format_addr(&switch.new),
+                "Sentinel announced a new master, checking quorum"
+            );
+            let queried_at = Instant::now();
+            match ctx.sentinels.query_master_after_switch(master).await {
+                Ok((addr, epoch)) => {
+                    let state = MasterState::Master { addr, epoch };
+                    publish_master(ctx, master, state, queried_at)
+                }
+                Err(err) => warn!(
+                    master,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Not switching master"
+                ),
+            }
+            // the old master is a replica now
+            refresh_replicas_after_event(ctx, master, target).await
+        }
+        SentinelEvent::ObjectivelyDown { instance, down } => {
+            // the poller would only notice a down master with its next round
+            if *down && instance.is_master() {
+                if let Err(err) = refresh_master(ctx, instance.master_name(), target).await {
+                    warn!(
+                        master = instance.master_name(),
+                        error.kind = err.kind(),
+                        error = %err,
+                        "Failed to get the master"
+                    );
+                }
+            }
+            refresh_replicas_after_event(ctx, instance.master_name(), target).await
+        }
+        SentinelEvent::ResetMaster { instance } | SentinelEvent::Monitor { instance } => {
+            if let Err(err) = refresh_master(ctx, instance.master_name(), target).await {
+                warn!(
+                    master = instance.master_name(),
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to get the master"
+                );
+            }
+            refresh_replicas_after_event(ctx, instance.master_name(), target).await
+        }
+        SentinelEvent::SubjectivelyDown { instance, .. }
+        | SentinelEvent::Other {
+            instance: Some(instance),
+            ..
+        } => refresh_replicas_after_event(ctx, instance.master_name(), target).await,
+        _ => Ok(()),
+    }
+}
+
+/// Waits for and handles the events of one subscription until it fails.
+async fn receive_events(
+    pubsub: PubSub,
+    ctx: &WorkerContext,
+    sentinel: &Sentinel,
+) -> Result<(), Error> {
+    let (mut sink, mut messages) = pubsub.split();
+    loop {
+        let msg = match timeout(HEARTBEAT_INTERVAL, messages.next()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                return Err(Error::RedisErr(
+                    io::Error::from(io::ErrorKind::ConnectionAborted).into(),
+                ))
+            }
+            Err(_) => {
+                // sentinels are quiet most of the time, so a half-open connection would go
+                // unnoticed without a request. Subscribing again to a subscribed pattern is a
+                // no-op that still needs a reply.
+                match timeout(READ_TIMEOUT, sink.psubscribe(ALL_EVENTS_PATTERN)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => return Err(Error::RedisErr(err)),
+                    Err(_) => return Err(timed_out()),
+                }
+                ctx.health.sentinel_reached();
+                continue;
+            }
+        };
+        ctx.health.sentinel_reached();
+        let value: String = match msg.get_payload() {
+            Ok(value) => value,
+            Err(err) => {
+                let err = Error::InvalidResponse(err.to_string());
+                warn!(
+                    sentinel = sentinel.addr,
+                    channel = msg.get_channel_name(),
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Received invalid event"
+                );
+                continue;
+            }
+        };
+        handle_event(ctx, sentinel, msg.get_channel_name(), value.as_str()).await?;
+    }
+}
+
+async fn listen_for_events(ctx: &WorkerContext, index: usize) -> Result<(), Error> {
+    let sentinel = &ctx.sentinels.sentinels[index];
+    let mut backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX);
+    loop {
+        let pubsub = match sentinel.psubscribe(ALL_EVENTS_PATTERN).await {
+            Ok(pubsub) => pubsub,
+            Err(err) => {
+                warn!(
+                    sentinel = sentinel.addr,
+                    pattern = ALL_EVENTS_PATTERN,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to subscribe"
+                );
+                sleep(backoff.next_delay()).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        ctx.health.sentinel_reached();
+
+        match receive_events(pubsub, ctx, sentinel).await {
+            Err(Error::ChannelClosed) => return Err(Error::ChannelClosed),
+            Err(err) => {
+                metrics::record_sentinel_error(&sentinel.addr);
+                warn!(
+                    sentinel = sentinel.addr,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to receive events"
+                );
+                sleep(backoff.next_delay()).await;
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+async fn poll_master_address(ctx: &WorkerContext) -> Result<(), Error> {
+    let mut backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX);
+    loop {
+        let mut failed = false;
+        for (master_name, target) in ctx.targets.iter() {
+            if let Err(err) = refresh_master(ctx, master_name, target).await {
+                warn!(
+                    master = master_name,
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Failed to get the master"
+                );
+                failed = true;
+            }
+            match refresh_replicas(ctx, master_name, target).await {
+                Err(Error::ChannelClosed) => return Err(Error::ChannelClosed),
+                Err(err) => {
+                    warn!(
+                        master = master_name,
+                        error.kind = err.kind(),
+                        error = %err,
+                        "Failed to get replicas"
+                    );
+                    failed = true;
+                }
+                Ok(()) => {}
+            }
+        }
+
+        // failed rounds are retried sooner, but never more often than the backoff allows
+        let delay = if failed {
+            backoff.next_delay().min(ctx.poll_interval)
+        } else {
+            backoff.reset();
+            ctx.poll_interval
+        };
+        sleep(delay).await;
+    }
+}
+
+/// Passes every conclusion on the master of a target on to the main loop as an update. Should
+/// the main loop fall behind, only the latest conclusion is passed on.
+async fn forward_master(ctx: &WorkerContext, index: usize) -> Result<(), Error> {
+    let (target, master) = &ctx.forwarded[index];
+    let mut master = master.clone();
+    while master.changed().await.is_ok() {
+        let state = master.borrow_and_update().clone();
+        let update = match state.map(|(state, _)| state) {
+            Some(MasterState::Master { addr, epoch }) => Update::Master {
+                target: target.clone(),
+                addr,
+                epoch,
+            },
+            Some(MasterState::Fenced) => Update::Fenced {
+                target: target.clone(),
+            },
+            None => continue,
+        };
+        send_update(&ctx.sender, update)?;
+    }
+    Ok(())
+}
+
+async fn run_worker(ctx: Arc<WorkerContext>, role: Role) -> Result<(), Error> {
+    match role {
+        Role::Listener(index) => listen_for_events(&ctx, index).await,
+        Role::Poller => poll_master_address(&ctx).await,
+        Role::Forwarder(index) => forward_master(&ctx, index).await,
+    }
+}
+
+/// Watches the worker tasks and restarts the ones that died. A worker that keeps dying is
+/// reported to the main loop, which shuts the process down so it gets restarted from a clean
+/// state instead of silently missing failovers. A worker that cannot deliver its updates anymore
+/// means the main loop is gone already. Cancelling the supervisor cancels its workers, stopping it
+/// waits for them to finish.
+async fn supervise(ctx: Arc<WorkerContext>, fatal: Sender<Error>, mut stop: oneshot::Receiver<()>) {
+    let roles = (0..ctx.sentinels.sentinels.len())
+        .map(Role::Listener)
+        .chain((0..ctx.forwarded.len()).map(Role::Forwarder))
+        .chain([Role::Poller]);
+    let mut workers = JoinSet::new();
+    // each running worker with the times it was restarted at
+    let mut running: HashMap<task::Id, (Role, Vec<Instant>)> = HashMap::new();
+    for role in roles {
+        let handle = workers.spawn(run_worker(ctx.clone(), role));
+        running.insert(handle.id(), (role, Vec::new()));
+    }
+
+    let mut heartbeat = interval(SUPERVISE_INTERVAL);
+    loop {
+        let (id, result) = tokio::select! {
+            _ = &mut stop => break,
+            _ = heartbeat.tick() => {
+                ctx.health.supervised();
+                continue;
+            }
+            Some(joined) = workers.join_next_with_id() => match joined {
+                Ok((id, result)) => (id, Ok(result)),
+                Err(err) => (err.id(), Err(err)),
+            },
+        };
+        let Some((role, mut restarts)) = running.remove(&id) else {
+            continue;
+        };
+        match result {
+            Ok(Err(Error::ChannelClosed)) => {
+                error!(?role, "Worker cannot deliver updates anymore, stopping");
+                break;
+            }
+            Ok(Err(err)) => warn!(
+                ?role,
+                error.kind = err.kind(),
+                error = %err,
+                "Worker failed"
+            ),
+            Ok(Ok(())) => warn!(?role, "Worker exited unexpectedly"),
+            Err(_) => warn!(?role, "Worker panicked"),
+        }
+
+        restarts.retain(|restart| restart.elapsed() < RESTART_WINDOW);
+        restarts.push(Instant::now());
+        if restarts.len() > MAX_RESTARTS {
+            error!(
+                ?role,
+                restarts = restarts.len(),
+                window = ?RESTART_WINDOW,
+                "Worker keeps dying, giving up"
+            );
+            let _ = fatal.send(Error::WorkersFailed(format!(
+                "{:?} worker died {} times within {:?}",
+                role,
+                restarts.len(),
+                RESTART_WINDOW
+            )));
+            break;
+        }
+        let handle = workers.spawn(run_worker(ctx.clone(), role));
+        running.insert(handle.id(), (role, restarts));
+        warn!(?role, "Restarted worker");
+    }
+    workers.shutdown().await;
+}
+
+impl Workers {
+    /// Starts one subscriber per sentinel, each of them handling the events of all the given
+    /// masters, and a poller that periodically asks the sentinels for all of them and their
+    /// replicas. A supervisor restarts them should they die, and sends to `fatal` once it gives
+    /// up on them.
+    pub fn spawn(
+        sentinels: Arc<SentinelSet>,
+        targets: Targets,
+        poll_interval: Duration,
+        fencing: Fencing,
+        sender: Sender<Update>,
+        fatal: Sender<Error>,
+    ) -> Self {
+        let services = targets.values().map(|target| target.key()).collect();
+        let mut masters = HashMap::new();
+        let mut forwarded = Vec::new();
+        for (master_name, target) in &targets {
+            let (master, receiver) = watch::channel(None);
+            masters.insert(master_name.clone(), master);
+            forwarded.push((target.clone(), receiver));
+        }
+        let ctx = Arc::new(WorkerContext {
+            sentinels,
+            sender,
+            targets,
+            poll_interval,
+            fencing,
+            no_master_since: Mutex::new(HashMap::new()),
+            masters,
+            last_masters: Mutex::new(HashMap::new()),
+            forwarded,
+            health: WorkerHealth::register(poll_interval, services),
+        });
+        let (stop, stopped) = oneshot::channel();
+        Workers {
+            supervisor: tokio::spawn(supervise(ctx, fatal, stopped)),
+            stop: Some(stop),
+        }
+    }
+
+    /// Cancels all worker tasks, they stop at their next await point.
+    pub fn stop(&self) {
+        self.supervisor.abort();
+    }
+
+    /// Cancels all worker tasks and waits until none of them can send an update anymore.
+    pub async fn shutdown(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        let _ = (&mut self.supervisor).await;
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(host: &str) -> RedisAddr {
+        (host.to_owned(), 6379)
+    }
+
+    fn elect(answers: &[(&str, bool, u64)], quorum: usize) -> Result<(RedisAddr, u64), Error> {
+        let answers = answers
+            .iter()
+            .map(|(host, down, epoch)| (addr(host), *down, *epoch))
+            .collect();
+        elect_master("mymaster", answers, 3, quorum)
+    }
+
+    #[test]
+    fn majority_wins() {
+        let answers = [
+            ("10.0.0.1", false, 1),
+            ("10.0.0.2", false, 2),
+            ("10.0.0.1", false, 1),
+        ];
+        assert!(matches!(
+            elect(&answers, 2),
+            Ok((master, 1)) if master == addr("10.0.0.1")
+        ));
+    }
+
+    #[test]
+    fn quorum_is_required() {
+        let answers = [("10.0.0.1", false, 1), ("10.0.0.2", false, 2)];
+        assert!(matches!(elect(&answers, 2), Err(Error::NoQuorum(_))));
+    }
+
+    #[test]
+    fn too_few_answers_are_unknown() {
+        let answers = [("10.0.0.1", false, 1)];
+        assert!(matches!(elect(&answers, 2), Err(Error::Unreachable(_))));
+        assert!(matches!(elect(&[], 1), Err(Error::Unreachable(_))));
+
+        let answers = [("10.0.0.1", true, 1)];
+        assert!(matches!(elect(&answers, 2), Err(Error::MasterDown(_))));
+    }
+
+    #[test]
+    fn tie_goes_to_the_newer_epoch() {
+        for answers in [
+            [("10.0.0.1", false, 1), ("10.0.0.2", false, 2)],
+            [("10.0.0.2", false, 2), ("10.0.0.1", false, 1)],
+        ] {
+            assert!(matches!(
+                elect(&answers, 1),
+                Ok((master, 2)) if master == addr("10.0.0.2")
+            ));
+        }
+    }
+
+    #[test]
+    fn exact_tie_is_no_agreement() {
+        let answers = [("10.0.0.1", false, 1), ("10.0.0.2", false, 1)];
+        assert!(matches!(elect(&answers, 1), Err(Error::NoQuorum(_))));
+    }
+
+    #[test]
+    fn newest_epoch_of_an_address_counts() {
+        let answers = [
+            ("10.0.0.1", false, 3),
+            ("10.0.0.1", false, 2),
+            ("10.0.0.2", false, 4),
+        ];
+        assert!(matches!(
+            elect(&answers, 2),
+            Ok((master, 3)) if master == addr("10.0.0.1")
+        ));
+    }
+
+    #[test]
+    fn down_master_is_not_elected() {
+        let answers = [
+            ("10.0.0.1", true, 1),
+            ("10.0.0.1", false, 1),
+            ("10.0.0.1", false, 1),
+        ];
+        assert!(matches!(elect(&answers, 2), Err(Error::MasterDown(_))));
+    }
+
+    fn master(host: &str, epoch: u64) -> MasterState {
+        MasterState::Master {
+            addr: addr(host),
+            epoch,
+        }
+    }
+
+    #[test]
+    fn reply_from_before_a_newer_master_is_stale() {
+        let queried_at = Instant::now();
+        let concluded_at = queried_at + Duration::from_secs(1);
+        let current = master("10.0.0.2", 2);
+        assert!(!master("10.0.0.1", 1).supersedes(queried_at, &current, concluded_at));
+        assert!(master("10.0.0.3", 3).supersedes(queried_at, &current, concluded_at));
+        assert!(master("10.0.0.2", 2).supersedes(queried_at, &current, concluded_at));
+        assert!(MasterState::Fenced.supersedes(queried_at, &current, concluded_at));
+        assert!(master("10.0.0.1", 1).supersedes(queried_at, &MasterState::Fenced, concluded_at));
+    }
+
+    #[test]
+    fn older_epoch_queried_later_wins() {
+        let concluded_at = Instant::now();
+        let queried_at = concluded_at + Duration::from_secs(1);
+        let current = master("10.0.0.2", 2);
+        assert!(master("10.0.0.1", 1).supersedes(queried_at, &current, concluded_at));
+    }
+
+    #[test]
+    fn parse_host_port_handles_ipv6() {
+        for (input, host) in [
+            ("[::1]:26379", "::1"),
+            ("::1:26379", "::1"),
+            ("[0:0:0:0:0:0:0:1]:26379", "::1"),
+            ("[2001:DB8:0:0::1]:26379", "2001:db8::1"),
+            ("10.0.0.1:26379", "10.0.0.1"),
+            ("sentinel.redis:26379", "sentinel.redis"),
+        ] {
+            assert!(
+                matches!(parse_host_port(input), Ok(parsed) if parsed == (host.to_owned(), 26379)),
+                "{} was parsed wrongly",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parse_host_port_requires_a_port() {
+        for input in ["[::1]", "sentinel", "sentinel:", "sentinel:65536"] {
+            assert!(
+                matches!(parse_host_port(input), Err(Error::InvalidConfig(_))),
+                "{} was accepted",
+                input
+            );
+        }
+    }
+}
@@ -0,0 +1,585 @@
+use std::{collections::BTreeMap, fmt::Debug, iter, net::IpAddr};
+
+use k8s_openapi::{
+    api::{
+        core::v1::{ObjectReference, Pod, Service, ServicePort, ServiceSpec},
+        discovery::v1::{Endpoint, EndpointConditions, EndpointPort, EndpointSlice},
+    },
+    apimachinery::pkg::apis::meta::v1::OwnerReference,
+    serde::{de::DeserializeOwned, Serialize},
+};
+use kube::{
+    api::{DeleteParams, ListParams, ObjectMeta, Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
+use tokio::net::lookup_host;
+use tracing::{debug, info, warn};
+
+use crate::{format_addr, metrics, Error, RedisAddr};
+
+pub const FIELD_MANAGER: &str = "redis-sentinel-service-controller";
+
+pub const DEFAULT_PORT_NAME: &str = "redis";
+const SERVICE_PORT: i32 = 6379;
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+const SERVICE_MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const ENDPOINT_SLICE_MANAGED_BY_LABEL: &str = "endpointslice.kubernetes.io/managed-by";
+
+#[derive(PartialEq)]
+pub struct ServiceTarget {
+    /// The name the followed master is monitored under by the sentinels.
+    pub master_name: String,
+    pub namespace: String,
+    pub name: String,
+    /// The Service to point to the healthy replicas of the master, if any.
+    pub replica_name: Option<String>,
+    pub port_name: String,
+    /// The object the service is created for, it becomes the owner of the managed objects.
+    pub owner: Option<OwnerReference>,
+}
+
+impl ServiceTarget {
+    /// Identifies the Service as `<namespace>/<name>`.
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.namespace, self.name)
+    }
+
+    /// The keys of all Services managed for the master, see [`ServiceTarget::key`].
+    pub fn service_keys(&self) -> Vec<String> {
+        let replica = self
+            .replica_name
+            .as_ref()
+            .map(|name| format!("{}/{}", self.namespace, name));
+        iter::once(self.key()).chain(replica).collect()
+    }
+}
+
+/// The address families endpoints are written for, each gets its own EndpointSlices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::Ipv4,
+            IpAddr::V6(_) => IpFamily::Ipv6,
+        }
+    }
+
+    /// The name of the family in Services and the address type of EndpointSlices.
+    fn name(self) -> &'static str {
+        match self {
+            IpFamily::Ipv4 => "IPv4",
+            IpFamily::Ipv6 => "IPv6",
+        }
+    }
+}
+
+/// How the hosts announced by the sentinels are turned into endpoint addresses.
+pub enum Resolution {
+    /// Looks the hosts up in the DNS of the controller.
+    Dns,
+    /// Maps the hosts to Pods in the namespace of the service, by IP, by hostname and subdomain
+    /// or, if given, by the value of a label matching the first DNS label of the host. The
+    /// endpoints then reference their Pods.
+    Pods { host_label: Option<String> },
+}
+
+pub struct Materializer {
+    client: Client,
+    force: bool,
+    resolution: Resolution,
+    /// The families to write endpoints for, the first one is the primary family of the Services.
+    ip_families: Vec<IpFamily>,
+}
+
+/// The Pod an endpoint address belongs to.
+#[derive(Clone, Debug, PartialEq)]
+struct PodTarget {
+    reference: ObjectReference,
+    node_name: Option<String>,
+    /// Whether the Pod passes its readiness probe, its endpoint is marked accordingly.
+    ready: bool,
+}
+
+/// The addresses of a service by port, each with the Pod it belongs to, if known.
+type Endpoints = BTreeMap<u16, BTreeMap<IpAddr, Option<PodTarget>>>;
+
+async fn resolve_dns(addr: &RedisAddr) -> Result<Vec<(IpAddr, Option<PodTarget>)>, Error> {
+    match lookup_host((addr.0.as_str(), addr.1)).await {
+        Ok(addrs) => Ok(addrs.map(|a| (a.ip(), None)).collect()),
+        Err(err) => Err(Error::ResolveErr(err)),
+    }
+}
+
+/// Whether the pod is the given host, which is either its IP, its hostname or a fully qualified
+/// name starting with `<hostname>.<subdomain>`.
+fn pod_matches_host(pod: &Pod, host: &str) -> bool {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return pod_ips(pod).contains(&ip);
+    }
+    let mut labels = host.split('.');
+    let spec = pod.spec.as_ref();
+    let hostname = spec
+        .and_then(|spec| spec.hostname.as_deref())
+        .unwrap_or(pod.metadata.name.as_deref().unwrap_or_default());
+    let subdomain = spec.and_then(|spec| spec.subdomain.as_deref());
+    labels.next() == Some(hostname)
+        && match (labels.next(), subdomain) {
+            (Some(label), Some(subdomain)) => label == subdomain,
+            _ => true,
+        }
+}
+
+fn pod_ips(pod: &Pod) -> Vec<IpAddr> {
+    let Some(status) = &pod.status else {
+        return Vec::new();
+    };
+    let ips = match &status.pod_ips {
+        Some(pod_ips) => pod_ips.iter().map(|pod_ip| pod_ip.ip.as_str()).collect(),
+        None => status.pod_ip.as_deref().into_iter().collect::<Vec<_>>(),
+    };
+    ips.into_iter().filter_map(|ip| ip.parse().ok()).collect()
+}
+
+fn pod_is_running(pod: &Pod) -> bool {
+    let phase = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref());
+    phase == Some("Running") && pod.metadata.deletion_timestamp.is_none()
+}
+
+fn pod_is_ready(pod: &Pod) -> bool {
+    let conditions = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.as_deref())
+        .unwrap_or_default();
+    conditions
+        .iter()
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+}
+
+fn pod_target(pod: &Pod) -> PodTarget {
+    PodTarget {
+        reference: ObjectReference {
+            kind: Some(Pod::kind(&()).into_owned()),
+            name: pod.metadata.name.clone(),
+            namespace: pod.metadata.namespace.clone(),
+            uid: pod.metadata.uid.clone(),
+            ..ObjectReference::default()
+        },
+        node_name: pod.spec.as_ref().and_then(|spec| spec.node_name.clone()),
+        ready: pod_is_ready(pod),
+    }
+}
+
+fn build_service(target: &ServiceTarget, name: &str, ip_families: &[IpFamily]) -> Service {
+    Service {
+        metadata: ObjectMeta {
+            name: Some(name.to_owned()),
+            namespace: Some(target.namespace.clone()),
+            owner_references: target.owner.clone().map(|owner| vec![owner]),
+            labels: Some(BTreeMap::from([(
+                SERVICE_MANAGED_BY_LABEL.to_owned(),
+                FIELD_MANAGER.to_owned(),
+            )])),
+            ..ObjectMeta::default()
+        },
+        spec: Some(ServiceSpec {
+            ports: Some(vec![ServicePort {
+                name: Some(target.port_name.clone()),
+                port: SERVICE_PORT,
+                protocol: Some("TCP".to_owned()),
+                ..ServicePort::default()
+            }]),
+            ip_families: Some(
+                ip_families
+                    .iter()
+                    .map(|family| family.name().to_owned())
+                    .collect(),
+            ),
+            ip_family_policy: Some(
+                if ip_families.len() > 1 {
+                    "PreferDualStack"
+                } else {
+                    "SingleStack"
+                }
+                .to_owned(),
+            ),
+            ..ServiceSpec::default()
+        }),
+        ..Service::default()
+    }
+}
+
+/// Endpoints of a slice share their ports and address type, so there is one slice per port and
+/// family. IPv4 slices keep the names they had before IPv6 was supported.
+fn build_endpoint_slice(
+    target: &ServiceTarget,
+    service_name: &str,
+    port: u16,
+    family: IpFamily,
+    addresses: &BTreeMap<IpAddr, Option<PodTarget>>,
+) -> EndpointSlice {
+    let name = match family {
+        IpFamily::Ipv4 => format!("{}-{}", service_name, port),
+        IpFamily::Ipv6 => format!("{}-{}-ipv6", service_name, port),
+    };
+    EndpointSlice {
+        metadata: ObjectMeta {
+            name: Some(name),
+            namespace: Some(target.namespace.clone()),
+            owner_references: target.owner.clone().map(|owner| vec![owner]),
+            labels: Some(BTreeMap::from([
+                (SERVICE_NAME_LABEL.to_owned(), service_name.to_owned()),
+                (
+                    ENDPOINT_SLICE_MANAGED_BY_LABEL.to_owned(),
+                    FIELD_MANAGER.to_owned(),
+                ),
+            ])),
+            ..ObjectMeta::default()
+        },
+        address_type: family.name().to_owned(),
+        endpoints: addresses
+            .iter()
+            .filter(|(ip, _)| IpFamily::of(ip) == family)
+            .map(|(ip, pod)| Endpoint {
+                addresses: vec![ip.to_string()],
+                conditions: Some(EndpointConditions {
+                    // addresses looked up in the DNS are taken as they are
+                    ready: Some(pod.as_ref().is_none_or(|pod| pod.ready)),
+                    ..EndpointConditions::default()
+                }),
+                target_ref: pod.as_ref().map(|pod| pod.reference.clone()),
+                node_name: pod.as_ref().and_then(|pod| pod.node_name.clone()),
+                ..Endpoint::default()
+            })
+            .collect(),
+        ports: Some(vec![EndpointPort {
+            name: Some(target.port_name.clone()),
+            port: Some(port as i32),
+            protocol: Some("TCP".to_owned()),
+            ..EndpointPort::default()
+        }]),
+    }
+}
+
+impl Materializer {
+    pub fn new(
+        client: Client,
+        force: bool,
+        resolution: Resolution,
+        ip_families: Vec<IpFamily>,
+    ) -> Self {
+        Materializer {
+            client,
+            force,
+            resolution,
+            ip_families,
+        }
+    }
+
+    async fn resolve_pods(
+        &self,
+        namespace: &str,
+        host: &str,
+        host_label: Option<&str>,
+    ) -> Result<Vec<(IpAddr, Option<PodTarget>)>, Error> {
+        let api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let params = match (host_label, host.parse::<IpAddr>()) {
+            (_, Ok(ip)) => ListParams::default().fields(&format!("status.podIP={}", ip)),
+            // announced names like redis-0.redis-headless.ns.svc.cluster.local carry the value
+            // of the label in their first DNS label only
+            (Some(label), Err(_)) => {
+                let value = host.split('.').next().unwrap_or_default();
+                ListParams::default().labels(&format!("{}={}", label, value))
+            }
+            (None, Err(_)) => ListParams::default(),
+        };
+        let pods = match api.list(&params).await {
+            Ok(pods) => pods,
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+
+        let mut resolved = Vec::new();
+        for pod in pods {
+            let matches = host_label.is_some() || pod_matches_host(&pod, host);
+            if !matches || !pod_is_running(&pod) {
+                continue;
+            }
+            let target = pod_target(&pod);
+            let ips = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => pod_ips(&pod),
+            };
+            resolved.extend(ips.into_iter().map(|ip| (ip, Some(target.clone()))));
+        }
+        if resolved.is_empty() {
+            return Err(Error::InvalidResponse(format!(
+                "No running pod found for {} in namespace {}",
+                host, namespace
+            )));
+        }
+        Ok(resolved)
+    }
+
+    /// Resolves the address to the addresses of the configured families to point the service to.
+    async fn resolve(
+        &self,
+        target: &ServiceTarget,
+        addr: &RedisAddr,
+    ) -> Result<BTreeMap<IpAddr, Option<PodTarget>>, Error> {
+        let resolved = match &self.resolution {
+            Resolution::Dns => resolve_dns(addr).await?,
+            Resolution::Pods { host_label } => {
+                self.resolve_pods(&target.namespace, &addr.0, host_label.as_deref())
+                    .await?
+            }
+        };
+        for (ip, pod) in &resolved {
+            debug!(host = addr.0, %ip, pod = ?pod.as_ref().map(|pod| &pod.reference.name), "Resolved");
+        }
+
+        let addresses: BTreeMap<IpAddr, Option<PodTarget>> = resolved
+            .into_iter()
+            .filter(|(ip, _)| self.ip_families.contains(&IpFamily::of(ip)))
+            .collect();
+        if addresses.is_empty() {
+            let families: Vec<&str> = self.ip_families.iter().map(|f| f.name()).collect();
+            return Err(Error::InvalidResponse(format!(
+                "No {} address found for {}",
+                families.join(" or "),
+                format_addr(addr)
+            )));
+        }
+        Ok(addresses)
+    }
+
+    /// Server-side applies the given object, unless an object with the same name already exists
+    /// and the given managed-by label does not point to us. In that case the object belongs to
+    /// someone else and is only taken over when forced.
+    async fn apply<K>(&self, obj: &K, managed_by_label: &str) -> Result<(), Error>
+    where
+        K: Resource<Scope = k8s_openapi::NamespaceResourceScope>
+            + Clone
+            + Debug
+            + DeserializeOwned
+            + Serialize,
+        K::DynamicType: Default,
+    {
+        let namespace = obj.namespace().unwrap_or_default();
+        let name = obj.name_any();
+        let api: Api<K> = Api::namespaced(self.client.clone(), namespace.as_str());
+
+        let existing = match api.get_opt(name.as_str()).await {
+            Ok(existing) => existing,
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+        if let Some(existing) = existing {
+            let manager = existing.labels().get(managed_by_label);
+            if manager.map(String::as_str) != Some(FIELD_MANAGER) {
+                if !self.force {
+                    return Err(Error::NotOwned(format!(
+                        "{} {}/{} is managed by {}",
+                        K::kind(&K::DynamicType::default()),
+                        namespace,
+                        name,
+                        manager.map(String::as_str).unwrap_or("someone else"),
+                    )));
+                }
+                info!(
+                    kind = K::kind(&K::DynamicType::default()).as_ref(),
+                    namespace,
+                    name,
+                    previous_manager = ?manager,
+                    "Taking over"
+                );
+            }
+        }
+
+        let mut params = PatchParams::apply(FIELD_MANAGER);
+        if self.force {
+            params = params.force();
+        }
+        let result = api.patch(name.as_str(), &params, &Patch::Apply(obj)).await;
+        metrics::record_kubernetes_write(
+            &K::kind(&K::DynamicType::default()),
+            "apply",
+            result.is_ok(),
+        );
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::KubeErr(err)),
+        }
+    }
+
+    /// Deletes the slices of the given service we created before, but which are not part of
+    /// the current set of slices anymore.
+    async fn delete_stale_endpoint_slices(
+        &self,
+        target: &ServiceTarget,
+        service_name: &str,
+        current: &[String],
+    ) -> Result<(), Error> {
+        let api: Api<EndpointSlice> = Api::namespaced(self.client.clone(), &target.namespace);
+        let selector = format!(
+            "{}={},{}={}",
+            SERVICE_NAME_LABEL, service_name, ENDPOINT_SLICE_MANAGED_BY_LABEL, FIELD_MANAGER
+        );
+        let slices = match api.list(&ListParams::default().labels(&selector)).await {
+            Ok(slices) => slices,
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+        for slice in slices {
+            let name = slice.name_any();
+            if current.contains(&name) {
+                continue;
+            }
+            let result = api.delete(&name, &DeleteParams::default()).await;
+            metrics::record_kubernetes_write(&EndpointSlice::kind(&()), "delete", result.is_ok());
+            if let Err(err) = result {
+                return Err(Error::KubeErr(err));
+            }
+            info!(
+                namespace = target.namespace,
+                name, "Deleted stale endpoint slice"
+            );
+        }
+        Ok(())
+    }
+
+    async fn materialize(
+        &self,
+        target: &ServiceTarget,
+        service_name: &str,
+        endpoints: &Endpoints,
+    ) -> Result<(), Error> {
+        self.apply(
+            &build_service(target, service_name, &self.ip_families),
+            SERVICE_MANAGED_BY_LABEL,
+        )
+        .await?;
+
+        let mut slice_names = Vec::new();
+        for (port, addresses) in endpoints {
+            for family in &self.ip_families {
+                if !addresses.keys().any(|ip| IpFamily::of(ip) == *family) {
+                    continue;
+                }
+                let slice = build_endpoint_slice(target, service_name, *port, *family, addresses);
+                slice_names.push(slice.name_any());
+                self.apply(&slice, ENDPOINT_SLICE_MANAGED_BY_LABEL).await?;
+            }
+        }
+        self.delete_stale_endpoint_slices(target, service_name, &slice_names)
+            .await?;
+
+        let addresses: BTreeMap<&u16, Vec<&IpAddr>> = endpoints
+            .iter()
+            .map(|(port, addresses)| (port, addresses.keys().collect()))
+            .collect();
+        info!(
+            master = target.master_name,
+            namespace = target.namespace,
+            service = service_name,
+            endpoints = ?addresses,
+            "Service updated"
+        );
+        Ok(())
+    }
+
+    pub async fn materialize_service(
+        &self,
+        target: &ServiceTarget,
+        addr: &RedisAddr,
+    ) -> Result<(), Error> {
+        let addresses = self.resolve(target, addr).await?;
+        self.materialize(target, &target.name, &BTreeMap::from([(addr.1, addresses)]))
+            .await
+    }
+
+    /// Leaves the service of the target without endpoints, so clients fail fast.
+    pub async fn clear_service(&self, target: &ServiceTarget) -> Result<(), Error> {
+        self.materialize(target, &target.name, &Endpoints::new())
+            .await
+    }
+
+    /// Leaves the replica service of the target, if any, without endpoints.
+    pub async fn clear_replicas(&self, target: &ServiceTarget) -> Result<(), Error> {
+        let Some(service_name) = &target.replica_name else {
+            return Ok(());
+        };
+        self.materialize(target, service_name, &Endpoints::new())
+            .await
+    }
+
+    /// Deletes a Service we created for the target before, along with its slices, once the
+    /// target no longer uses it. A Service managed by someone else is left alone.
+    pub async fn delete_service(
+        &self,
+        target: &ServiceTarget,
+        service_name: &str,
+    ) -> Result<(), Error> {
+        let api: Api<Service> = Api::namespaced(self.client.clone(), &target.namespace);
+        let existing = match api.get_opt(service_name).await {
+            Ok(existing) => existing,
+            Err(err) => return Err(Error::KubeErr(err)),
+        };
+        if let Some(existing) = existing {
+            let manager = existing.labels().get(SERVICE_MANAGED_BY_LABEL);
+            if manager.map(String::as_str) != Some(FIELD_MANAGER) {
+                warn!(
+                    namespace = target.namespace,
+                    service = service_name,
+                    manager,
+                    "Not deleting a Service managed by someone else"
+                );
+                return Ok(());
+            }
+            let result = api.delete(service_name, &DeleteParams::default()).await;
+            metrics::record_kubernetes_write(&Service::kind(&()), "delete", result.is_ok());
+            if let Err(err) = result {
+                return Err(Error::KubeErr(err));
+            }
+            info!(
+                namespace = target.namespace,
+                service = service_name,
+                "Deleted Service"
+            );
+        }
+        self.delete_stale_endpoint_slices(target, service_name, &[])
+            .await
+    }
+
+    /// Points the replica service of the target to the given replicas. Replicas that cannot be
+    /// resolved are left out rather than failing the whole update.
+    pub async fn materialize_replicas(
+        &self,
+        target: &ServiceTarget,
+        addrs: &[RedisAddr],
+    ) -> Result<(), Error> {
+        let Some(service_name) = &target.replica_name else {
+            return Ok(());
+        };
+
+        let mut endpoints = Endpoints::new();
+        for addr in addrs {
+            match self.resolve(target, addr).await {
+                Ok(addresses) => endpoints.entry(addr.1).or_default().extend(addresses),
+                Err(err) => warn!(
+                    master = target.master_name,
+                    replica = format_addr(addr),
+                    error.kind = err.kind(),
+                    error = %err,
+                    "Leaving out unresolvable replica"
+                ),
+            }
+        }
+        self.materialize(target, service_name, &endpoints).await
+    }
+}